@@ -15,7 +15,7 @@ use hal::{
 };
 use palantir::{self, feather_bus as bus, Palantir, SlaveAddresses};
 
-use bus::UartBus;
+use bus::FeatherUartBus;
 
 const SLAVES: SlaveAddresses = [2, 0, 0, 0, 0, 0, 0];
 
@@ -26,7 +26,7 @@ type ErrorLEDPin = Pb8<Output<PushPull>>;
 #[rtfm::app(device = hal::pac)]
 const APP: () = {
     struct Resources {
-        palantir: Palantir<UartBus<ReceiveEnablePin>>,
+        palantir: Palantir<FeatherUartBus<ReceiveEnablePin>>,
         sercom0: hal::pac::SERCOM0,
         status_led: StatusLEDPin,
         error_led: ErrorLEDPin,
@@ -52,7 +52,7 @@ const APP: () = {
         let mut transmit_enable = pins.a4.into_push_pull_output(&mut pins.port);
         transmit_enable.set_low().unwrap();
 
-        let uart = UartBus::easy_new(
+        let uart = FeatherUartBus::easy_new(
             &mut clocks,
             peripherals.SERCOM0,
             &mut peripherals.PM,
@@ -0,0 +1,38 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use palantir::{Bus, Palantir};
+
+/// Feeds the fuzzer's input to the parser two bytes per bus word.
+struct FuzzBus<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Bus for FuzzBus<'a> {
+    type Error = ();
+
+    fn send(&mut self, _data: &[u16]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn read(&mut self) -> nb::Result<u16, Self::Error> {
+        if self.data.len() < 2 {
+            return Err(nb::Error::Other(()));
+        }
+        let word = u16::from_le_bytes([self.data[0], self.data[1]]);
+        self.data = &self.data[2..];
+        Ok(word)
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    // A monitor accepts every frame, so every address byte reaches the receiver.
+    let mut monitor = Palantir::new_monitor(FuzzBus { data });
+    for _ in 0..data.len() / 2 {
+        let _ = monitor.poll_frame();
+    }
+
+    let mut slave = Palantir::new_slave(2, FuzzBus { data });
+    for _ in 0..data.len() / 2 {
+        let _ = slave.poll();
+    }
+});
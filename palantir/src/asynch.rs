@@ -0,0 +1,311 @@
+//! `async` front-end to `Palantir`.
+//!
+//! Nothing here depends on a particular executor: on the boards it runs under
+//! embassy or similar, on the host under any `block_on`. The bus has to wake
+//! the task when a word arrives, see `AsyncBus`, and anything with a timeout
+//! needs an `AsyncClock`.
+
+use core::future::poll_fn;
+use core::task::{Context, Poll};
+
+use crate::common::*;
+use crate::error::Error;
+use crate::messages::{DiscoveryRequestData, Frame, Message};
+use crate::time::{Clock, Instant};
+use crate::{check_discovery_ack, Bus, Palantir};
+
+pub trait AsyncBus: Bus {
+    /// Like `Bus::read`, but instead of `WouldBlock` returns `Pending` and
+    /// has `cx`'s waker woken once a word arrives, typically from the receive
+    /// interrupt.
+    fn poll_read(&mut self, cx: &mut Context<'_>) -> Poll<Result<u16, Self::Error>>;
+}
+
+pub trait AsyncClock: Clock {
+    /// `Ready` once `now` has reached `deadline`. Otherwise has `cx`'s waker
+    /// woken no later than `deadline`.
+    fn poll_until(&self, deadline: Instant, cx: &mut Context<'_>) -> Poll<()>;
+}
+
+impl<C: AsyncClock> AsyncClock for &C {
+    fn poll_until(&self, deadline: Instant, cx: &mut Context<'_>) -> Poll<()> {
+        (*self).poll_until(deadline, cx)
+    }
+}
+
+#[derive(Debug)]
+pub struct AsyncPalantir<B: Bus, C, const Q: usize = DEFAULT_RX_QUEUE_LEN> {
+    palantir: Palantir<B, Q>,
+    clock: C,
+}
+
+impl<B: AsyncBus, C: AsyncClock, const Q: usize> AsyncPalantir<B, C, Q> {
+    pub fn new(palantir: Palantir<B, Q>, clock: C) -> Self {
+        AsyncPalantir { palantir, clock }
+    }
+
+    /// For the settings and the non-blocking API, e.g. `respond`.
+    pub fn inner(&mut self) -> &mut Palantir<B, Q> {
+        &mut self.palantir
+    }
+
+    pub fn into_inner(self) -> Palantir<B, Q> {
+        self.palantir
+    }
+
+    /// Transmitting doesn't yield, a frame is short enough to just push out.
+    pub async fn send(
+        &mut self,
+        address: Address,
+        message: &Message,
+    ) -> Result<(), Error<B::Error>> {
+        self.palantir.send(address, message)
+    }
+
+    /// Waits for the next frame addressed to this node.
+    pub async fn recv(&mut self) -> Result<Frame, Error<B::Error>> {
+        poll_fn(|cx| self.poll_frame(cx)).await
+    }
+
+    /// Sends `message` as a request and waits for the response, at most the
+    /// request timeout set on the inner `Palantir`. Other frames that arrive
+    /// meanwhile are queued for `recv`.
+    pub async fn request(
+        &mut self,
+        address: Address,
+        message: &Message,
+    ) -> Result<Message, Error<B::Error>> {
+        let now = self.clock.now();
+        let pending = self.palantir.request(address, message, now)?;
+        let deadline = now.wrapping_add(self.palantir.request_timeout_us);
+        poll_fn(|cx| loop {
+            match self.palantir.response(&pending, self.clock.now()) {
+                Ok(message) => return Poll::Ready(Ok(message)),
+                Err(nb::Error::Other(e)) => return Poll::Ready(Err(e)),
+                Err(nb::Error::WouldBlock) => (),
+            }
+            // A word at a time, the response itself never comes out as a frame
+            match self.poll_word(cx) {
+                Poll::Ready(Ok(Some(frame))) => self.palantir.inbox.push(frame),
+                Poll::Ready(Ok(None)) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => {
+                    if self.clock.poll_until(deadline, cx).is_pending() {
+                        return Poll::Pending;
+                    }
+                    // `response` reports the timeout next time round
+                }
+            }
+        })
+        .await
+    }
+
+    /// Async version of `Palantir::discover_devices`. A slave that doesn't
+    /// answer within the request timeout fails with `NotDiscovered`.
+    pub async fn discover_devices(&mut self) -> Result<(), Error<B::Error>> {
+        let slaves = *self.palantir.slaves().ok_or(Error::NotMaster)?;
+        for slave in slaves.iter() {
+            if *slave == 0 {
+                continue;
+            }
+
+            let message = Message::DiscoveryRequest(DiscoveryRequestData::new(*slave));
+            self.palantir.send(*slave, &message)?;
+            let deadline = self
+                .clock
+                .now()
+                .wrapping_add(self.palantir.request_timeout_us);
+            // Read errors are skipped like in the blocking version
+            let frame = loop {
+                match self.recv_until(deadline).await {
+                    Ok(frame) => break frame,
+                    Err(Error::Timeout) => return Err(Error::NotDiscovered(*slave)),
+                    Err(_) => continue,
+                }
+            };
+            let version = check_discovery_ack(*slave, frame.message)?;
+            self.palantir.set_peer_version(*slave, version);
+        }
+        Ok(())
+    }
+
+    /// Async version of `Palantir::discovery_mode`.
+    pub async fn discovery_mode(&mut self) -> Result<(), Error<B::Error>> {
+        let msg = loop {
+            if let Ok(frame) = self.recv().await {
+                break frame.message;
+            }
+        };
+        self.palantir.acknowledge_discovery(msg)
+    }
+
+    /// `recv`, failing with `Timeout` once `deadline` passes.
+    async fn recv_until(&mut self, deadline: Instant) -> Result<Frame, Error<B::Error>> {
+        poll_fn(|cx| match self.poll_frame(cx) {
+            Poll::Pending => self
+                .clock
+                .poll_until(deadline, cx)
+                .map(|_| Err(Error::Timeout)),
+            ready => ready,
+        })
+        .await
+    }
+
+    fn poll_frame(&mut self, cx: &mut Context<'_>) -> Poll<Result<Frame, Error<B::Error>>> {
+        if let Some(frame) = self.palantir.inbox.pop() {
+            return Poll::Ready(Ok(frame));
+        }
+        loop {
+            match self.poll_word(cx) {
+                Poll::Ready(Ok(Some(frame))) => return Poll::Ready(Ok(frame)),
+                Poll::Ready(Ok(None)) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    /// Reads one word, `Some` if it completed a frame.
+    fn poll_word(&mut self, cx: &mut Context<'_>) -> Poll<Result<Option<Frame>, Error<B::Error>>> {
+        let word = match self.palantir.bus.poll_read(cx) {
+            Poll::Ready(Ok(word)) => word,
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(self.palantir.bus_error(e))),
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(match self.palantir.receive(word) {
+            Ok(frame) => Ok(Some(frame)),
+            Err(nb::Error::WouldBlock) => Ok(None),
+            Err(nb::Error::Other(e)) => Err(e),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::{PingData, PongData};
+    use crate::tests::{next_frame, MockBus};
+    use crate::Transaction;
+    use core::cell::Cell;
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::Waker;
+
+    impl AsyncBus for MockBus {
+        fn poll_read(&mut self, _cx: &mut Context<'_>) -> Poll<Result<u16, Self::Error>> {
+            // Tests poll by hand, so there's no waker to remember
+            match self.read() {
+                Ok(word) => Poll::Ready(Ok(word)),
+                Err(nb::Error::WouldBlock) => Poll::Pending,
+                Err(nb::Error::Other(e)) => Poll::Ready(Err(e)),
+            }
+        }
+    }
+
+    struct MockClock(Cell<Instant>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    impl AsyncClock for MockClock {
+        fn poll_until(&self, deadline: Instant, _cx: &mut Context<'_>) -> Poll<()> {
+            if crate::time::is_due(deadline, self.now()) {
+                Poll::Ready(())
+            } else {
+                Poll::Pending
+            }
+        }
+    }
+
+    fn poll_once<F: Future>(future: core::pin::Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    fn master_and_slave(
+        clock: &MockClock,
+    ) -> (AsyncPalantir<MockBus, &MockClock>, Palantir<MockBus>) {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let master = Palantir::discovered_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        (
+            AsyncPalantir::new(master, clock),
+            Palantir::discovered_slave(2, slave_bus),
+        )
+    }
+
+    #[test]
+    fn request_waits_for_response() {
+        let clock = MockClock(Cell::new(0));
+        let (mut master, mut slave) = master_and_slave(&clock);
+
+        let ping = Message::Ping(PingData::new(7));
+        {
+            let mut request = pin!(master.request(2, &ping));
+            assert!(poll_once(request.as_mut()).is_pending());
+
+            let frame = next_frame(&mut slave).unwrap();
+            let transaction = match frame.transaction {
+                Some(Transaction::Request(id)) => id,
+                _ => panic!("expected a request"),
+            };
+            // Something unrelated gets in first
+            slave.send(MASTER_ADDRESS, &Message::Poll).unwrap();
+            let pong = Message::Pong(PongData::new(2, 7));
+            slave.respond(MASTER_ADDRESS, transaction, &pong).unwrap();
+
+            assert_eq!(poll_once(request.as_mut()), Poll::Ready(Ok(pong)));
+        }
+
+        let mut recv = pin!(master.recv());
+        match poll_once(recv.as_mut()) {
+            Poll::Ready(Ok(frame)) => assert_eq!(frame.message, Message::Poll),
+            _ => panic!("unrelated frame was lost"),
+        }
+    }
+
+    #[test]
+    fn request_times_out() {
+        let clock = MockClock(Cell::new(0));
+        let (mut master, _slave) = master_and_slave(&clock);
+        master.inner().set_request_timeout(1_000);
+
+        let mut request = pin!(master.request(2, &Message::Poll));
+        assert!(poll_once(request.as_mut()).is_pending());
+        clock.0.set(999);
+        assert!(poll_once(request.as_mut()).is_pending());
+        clock.0.set(1_000);
+        assert_eq!(
+            poll_once(request.as_mut()),
+            Poll::Ready(Err(Error::Timeout))
+        );
+    }
+
+    #[test]
+    fn discovery() {
+        let clock = MockClock(Cell::new(0));
+        let (mut master, slave) = master_and_slave(&clock);
+        let mut slave = AsyncPalantir::new(slave, &clock);
+
+        let mut discover = pin!(master.discover_devices());
+        let mut answer = pin!(slave.discovery_mode());
+        assert!(poll_once(discover.as_mut()).is_pending());
+        assert_eq!(poll_once(answer.as_mut()), Poll::Ready(Ok(())));
+        assert_eq!(poll_once(discover.as_mut()), Poll::Ready(Ok(())));
+    }
+
+    #[test]
+    fn discovery_timeout() {
+        let clock = MockClock(Cell::new(0));
+        let (mut master, _slave) = master_and_slave(&clock);
+
+        let mut discover = pin!(master.discover_devices());
+        assert!(poll_once(discover.as_mut()).is_pending());
+        clock.0.set(crate::DEFAULT_REQUEST_TIMEOUT_US);
+        assert_eq!(
+            poll_once(discover.as_mut()),
+            Poll::Ready(Err(Error::NotDiscovered(2)))
+        );
+    }
+}
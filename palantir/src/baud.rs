@@ -0,0 +1,742 @@
+//! Runtime baud rate negotiation.
+//!
+//! Every node boots at `BAUD_RATES[0]`. The master's `BaudNegotiator` asks
+//! each slave which rates it supports, picks the fastest one they all share
+//! and tells every slave to switch at the same moment. It then checks each
+//! slave at the new rate and, once all have answered, confirms the switch
+//! with each one, asking again until it acknowledges. A slave's
+//! `BaudFollower` goes back to the old rate by itself if no confirmation
+//! arrives, and so does the master, so a slave that can't keep up costs the
+//! speed-up but never splits the bus.
+//!
+//! Expect a few line errors around the switch, from words sent at one rate
+//! and read at the other.
+
+use crate::common::*;
+use crate::error::Error;
+use crate::messages::{BaudRatesData, Frame, Message, SwitchBaudData};
+use crate::time::{elapsed, is_due, Instant};
+use crate::{Bus, Palantir, PendingResponse, Transaction};
+
+/// Rates that can be negotiated, slowest first. Bit `i` of
+/// `BaudRatesData::rates` stands for `BAUD_RATES[i]`.
+pub const BAUD_RATES: [u32; 8] = [
+    9_600, 19_200, 38_400, 57_600, 115_200, 250_000, 500_000, 1_000_000,
+];
+/// How far ahead the master schedules the switch. Has to leave time to tell
+/// every slave at the boot rate.
+pub const DEFAULT_SWITCH_DELAY_US: u32 = 200_000;
+/// How long slaves run at the new rate without `ConfirmBaud` before going
+/// back.
+pub const DEFAULT_CONFIRM_TIMEOUT_US: u32 = 200_000;
+/// Slaves switch a little after the master, by however late they read the
+/// announcement. The master waits this long before checking on them.
+const SETTLE_US: u32 = 1_000;
+/// How often a slave is asked for its rates before negotiation gives up.
+const MAX_QUERY_ATTEMPTS: u8 = 3;
+
+/// Bit `i` set for every `BAUD_RATES[i]` the bus supports. The boot rate
+/// always is.
+fn supported_rates<B: Bus>(bus: &B) -> u8 {
+    BAUD_RATES
+        .iter()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, baud)| bus.supports_baud(**baud))
+        .fold(1, |rates, (i, _)| rates | 1 << i)
+}
+
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BaudEvent {
+    /// Every slave answered at this rate and was told to keep it.
+    Switched(u32),
+    /// The bus stays at its current rate. Nothing faster is supported by
+    /// every slave, or one didn't say what it supports.
+    Unchanged,
+    /// This slave didn't answer at the new rate, so the whole bus is back at
+    /// the old one.
+    FellBack(Address),
+    /// This slave answered at the new rate but never acknowledged
+    /// `ConfirmBaud`, so it has gone back to the old rate on its own.
+    Unconfirmed(Address),
+    /// A frame that isn't part of the negotiation.
+    Frame(Frame),
+}
+
+#[derive(Clone, Copy, Debug)]
+enum State {
+    /// Collecting supported rates. `next` indexes the slaves, `attempts`
+    /// counts the questions put to it so far.
+    Querying {
+        next: usize,
+        rates: u8,
+        attempts: u8,
+        pending: Option<PendingResponse>,
+    },
+    /// Telling each slave to switch to `baud` at `at`.
+    Announcing {
+        next: usize,
+        baud: u32,
+        at: Instant,
+    },
+    /// Checking each slave answers at `baud`.
+    Verifying {
+        next: usize,
+        baud: u32,
+        at: Instant,
+        pending: Option<PendingResponse>,
+    },
+    /// Confirming `baud` with each slave.
+    Confirming {
+        next: usize,
+        baud: u32,
+        at: Instant,
+        pending: Option<PendingResponse>,
+    },
+    /// Back at the old rate, waiting for the slaves to get there too.
+    FallingBack {
+        failed: Address,
+        until: Instant,
+    },
+    Done,
+}
+
+/// Master side: negotiates the fastest rate every slave supports.
+#[derive(Debug)]
+pub struct BaudNegotiator {
+    slaves: [Address; MAX_SLAVES],
+    len: usize,
+    baud: u32,
+    state: State,
+    switch_delay_us: u32,
+    confirm_timeout_us: u32,
+}
+
+impl BaudNegotiator {
+    /// Negotiates with every slave in `slaves`, which should already have
+    /// been discovered and run a `BaudFollower`.
+    pub fn new(slaves: &SlaveAddresses) -> Self {
+        let mut present = [0; MAX_SLAVES];
+        let mut len = 0;
+        for address in slaves.iter().filter(|a| **a != 0) {
+            present[len] = *address;
+            len += 1;
+        }
+        BaudNegotiator {
+            slaves: present,
+            len,
+            baud: BAUD_RATES[0],
+            state: State::Querying {
+                next: 0,
+                rates: u8::MAX,
+                attempts: 0,
+                pending: None,
+            },
+            switch_delay_us: DEFAULT_SWITCH_DELAY_US,
+            confirm_timeout_us: DEFAULT_CONFIRM_TIMEOUT_US,
+        }
+    }
+
+    pub fn set_switch_delay(&mut self, delay_us: u32) {
+        self.switch_delay_us = delay_us;
+    }
+
+    /// Has to leave time to check and then confirm every slave at the new
+    /// rate, each one can take up to the request timeout.
+    pub fn set_confirm_timeout(&mut self, timeout_us: u32) {
+        self.confirm_timeout_us = timeout_us;
+    }
+
+    /// The rate the bus runs at once negotiation is done.
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Done)
+    }
+
+    /// Moves negotiation on by one step without waiting. At most one frame
+    /// goes out per call.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<BaudEvent>, Error<B::Error>> {
+        match palantir.read() {
+            Ok(frame) => return Ok(Some(BaudEvent::Frame(frame))),
+            Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(e),
+        }
+
+        match self.state {
+            State::Querying {
+                next,
+                rates,
+                attempts,
+                pending,
+            } => {
+                let slave = match self.slaves[..self.len].get(next) {
+                    Some(slave) => *slave,
+                    None => return Ok(self.pick(palantir, rates, now)),
+                };
+                let pending = match pending {
+                    Some(pending) => pending,
+                    None => {
+                        let pending = palantir.request(slave, &Message::GetBaudRates, now)?;
+                        self.state = State::Querying {
+                            next,
+                            rates,
+                            attempts: attempts + 1,
+                            pending: Some(pending),
+                        };
+                        return Ok(None);
+                    }
+                };
+                match palantir.response(&pending, now) {
+                    Ok(Message::BaudRates(data)) if data.responder_address() == slave => {
+                        self.state = State::Querying {
+                            next: next + 1,
+                            rates: rates & data.rates(),
+                            attempts: 0,
+                            pending: None,
+                        };
+                    }
+                    // Not an answer to the question, ask again
+                    Ok(_) if attempts < MAX_QUERY_ATTEMPTS => {
+                        self.state = State::Querying {
+                            next,
+                            rates,
+                            attempts,
+                            pending: None,
+                        };
+                    }
+                    Ok(_) => {
+                        self.state = State::Done;
+                        return Ok(Some(BaudEvent::Unchanged));
+                    }
+                    Err(nb::Error::Other(Error::Timeout)) => {
+                        self.state = State::Done;
+                        return Ok(Some(BaudEvent::Unchanged));
+                    }
+                    Err(nb::Error::WouldBlock) => (),
+                    Err(nb::Error::Other(e)) => return Err(e),
+                }
+            }
+            State::Announcing { next, baud, at } => match self.slaves[..self.len].get(next) {
+                Some(slave) => {
+                    let delay = if is_due(at, now) { 0 } else { elapsed(now, at) };
+                    let data = SwitchBaudData::new(baud, delay, self.confirm_timeout_us);
+                    palantir.send(*slave, &Message::SwitchBaud(data))?;
+                    self.state = State::Announcing {
+                        next: next + 1,
+                        baud,
+                        at,
+                    };
+                }
+                None if is_due(at, now) => {
+                    palantir.switch_baud(baud);
+                    self.state = State::Verifying {
+                        next: 0,
+                        baud,
+                        at,
+                        pending: None,
+                    };
+                }
+                None => (),
+            },
+            State::Verifying {
+                next,
+                baud,
+                at,
+                pending,
+            } => {
+                let slave = match self.slaves[..self.len].get(next) {
+                    Some(slave) => *slave,
+                    None => {
+                        self.state = State::Confirming {
+                            next: 0,
+                            baud,
+                            at,
+                            pending: None,
+                        };
+                        return Ok(None);
+                    }
+                };
+                if elapsed(at, now) >= self.confirm_timeout_us {
+                    // The slaves are going back by now, confirming would be too late
+                    self.fall_back(palantir, slave, at);
+                    return Ok(None);
+                }
+                let pending = match pending {
+                    Some(pending) => pending,
+                    None if is_due(at.wrapping_add(SETTLE_US), now) => {
+                        let pending = palantir.request(slave, &Message::GetBaudRates, now)?;
+                        self.state = State::Verifying {
+                            next,
+                            baud,
+                            at,
+                            pending: Some(pending),
+                        };
+                        return Ok(None);
+                    }
+                    None => return Ok(None),
+                };
+                match palantir.response(&pending, now) {
+                    Ok(Message::BaudRates(data)) if data.responder_address() == slave => {
+                        self.state = State::Verifying {
+                            next: next + 1,
+                            baud,
+                            at,
+                            pending: None,
+                        };
+                    }
+                    // Proves nothing, ask again until the confirm timeout
+                    Ok(_) => {
+                        self.state = State::Verifying {
+                            next,
+                            baud,
+                            at,
+                            pending: None,
+                        };
+                    }
+                    Err(nb::Error::Other(Error::Timeout)) => self.fall_back(palantir, slave, at),
+                    Err(nb::Error::WouldBlock) => (),
+                    Err(nb::Error::Other(e)) => return Err(e),
+                }
+            }
+            State::Confirming {
+                next,
+                baud,
+                at,
+                pending,
+            } => {
+                let slave = match self.slaves[..self.len].get(next) {
+                    Some(slave) => *slave,
+                    None => {
+                        self.baud = baud;
+                        self.state = State::Done;
+                        return Ok(Some(BaudEvent::Switched(baud)));
+                    }
+                };
+                if elapsed(at, now) >= self.confirm_timeout_us {
+                    // Its trial is over, it won't listen at this rate any more
+                    self.state = State::Confirming {
+                        next: next + 1,
+                        baud,
+                        at,
+                        pending: None,
+                    };
+                    return Ok(Some(BaudEvent::Unconfirmed(slave)));
+                }
+                let pending = match pending {
+                    Some(pending) => pending,
+                    None => {
+                        let pending = palantir.request(slave, &Message::ConfirmBaud, now)?;
+                        self.state = State::Confirming {
+                            next,
+                            baud,
+                            at,
+                            pending: Some(pending),
+                        };
+                        return Ok(None);
+                    }
+                };
+                match palantir.response(&pending, now) {
+                    Ok(Message::ConfirmBaud) => {
+                        self.state = State::Confirming {
+                            next: next + 1,
+                            baud,
+                            at,
+                            pending: None,
+                        };
+                    }
+                    // Lost on the way there or back, confirm again
+                    Ok(_) | Err(nb::Error::Other(Error::Timeout)) => {
+                        self.state = State::Confirming {
+                            next,
+                            baud,
+                            at,
+                            pending: None,
+                        };
+                    }
+                    Err(nb::Error::WouldBlock) => (),
+                    Err(nb::Error::Other(e)) => return Err(e),
+                }
+            }
+            State::FallingBack { failed, until } if is_due(until, now) => {
+                self.state = State::Done;
+                return Ok(Some(BaudEvent::FellBack(failed)));
+            }
+            State::FallingBack { .. } | State::Done => (),
+        }
+        Ok(None)
+    }
+
+    /// Schedules the switch to the fastest rate in `rates` this bus also
+    /// supports, if that's faster than now.
+    fn pick<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &Palantir<B, Q>,
+        rates: u8,
+        now: Instant,
+    ) -> Option<BaudEvent> {
+        let common = rates & supported_rates(&palantir.bus) | 1;
+        let baud = BAUD_RATES[7 - common.leading_zeros() as usize];
+        if baud <= self.baud {
+            self.state = State::Done;
+            return Some(BaudEvent::Unchanged);
+        }
+        self.state = State::Announcing {
+            next: 0,
+            baud,
+            at: now.wrapping_add(self.switch_delay_us),
+        };
+        None
+    }
+
+    fn fall_back<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        failed: Address,
+        at: Instant,
+    ) {
+        palantir.switch_baud(self.baud);
+        self.state = State::FallingBack {
+            failed,
+            // Slaves that switched late also go back late
+            until: at.wrapping_add(self.confirm_timeout_us + SETTLE_US),
+        };
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Switch {
+    Steady,
+    Scheduled {
+        baud: u32,
+        at: Instant,
+        confirm_timeout_us: u32,
+    },
+    /// Running at a new rate, going back to `previous` at `until` unless
+    /// confirmed.
+    Trial {
+        previous: u32,
+        until: Instant,
+    },
+}
+
+/// Slave side: tells the master which rates this bus supports and switches
+/// when told to.
+#[derive(Debug)]
+pub struct BaudFollower {
+    baud: u32,
+    switch: Switch,
+}
+
+impl Default for BaudFollower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BaudFollower {
+    pub fn new() -> Self {
+        BaudFollower {
+            baud: BAUD_RATES[0],
+            switch: Switch::Steady,
+        }
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    /// Reads the bus, handling negotiation itself. Other frames are handed
+    /// back.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<Frame>, Error<B::Error>> {
+        self.check(palantir, now);
+        match palantir.read() {
+            Ok(frame) => self.handle(palantir, frame, now),
+            Err(nb::Error::WouldBlock) => Ok(None),
+            Err(nb::Error::Other(e)) => Err(e),
+        }
+    }
+
+    /// For frames read elsewhere, e.g. handed back by `Watchdog::poll`.
+    /// Returns `frame` unless it was part of negotiation. `check` has to be
+    /// called as well.
+    pub fn handle<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        frame: Frame,
+        now: Instant,
+    ) -> Result<Option<Frame>, Error<B::Error>> {
+        match frame.message {
+            Message::GetBaudRates => {
+                let rates = BaudRatesData::new(palantir.address(), supported_rates(&palantir.bus));
+                let rates = Message::BaudRates(rates);
+                match frame.transaction {
+                    Some(Transaction::Request(id)) => {
+                        palantir.respond(MASTER_ADDRESS, id, &rates)?
+                    }
+                    _ => palantir.send(MASTER_ADDRESS, &rates)?,
+                }
+            }
+            Message::SwitchBaud(data) => {
+                // A rate this bus can't do is ignored, the master then falls back
+                let trial = matches!(self.switch, Switch::Trial { .. });
+                if !trial && palantir.bus.supports_baud(data.baud()) {
+                    self.switch = Switch::Scheduled {
+                        baud: data.baud(),
+                        at: now.wrapping_add(data.delay_us()),
+                        confirm_timeout_us: data.confirm_timeout_us(),
+                    };
+                }
+            }
+            Message::ConfirmBaud => {
+                if let Switch::Trial { .. } = self.switch {
+                    self.switch = Switch::Steady;
+                }
+                // Acknowledged every time, the master asks again if this is lost
+                if let Some(Transaction::Request(id)) = frame.transaction {
+                    palantir.respond(MASTER_ADDRESS, id, &Message::ConfirmBaud)?;
+                }
+            }
+            _ => return Ok(Some(frame)),
+        }
+        Ok(None)
+    }
+
+    /// Switches when the scheduled time comes, and back if the switch isn't
+    /// confirmed in time.
+    pub fn check<B: Bus, const Q: usize>(&mut self, palantir: &mut Palantir<B, Q>, now: Instant) {
+        match self.switch {
+            Switch::Scheduled {
+                baud,
+                at,
+                confirm_timeout_us,
+            } if is_due(at, now) => {
+                palantir.switch_baud(baud);
+                self.switch = Switch::Trial {
+                    previous: self.baud,
+                    until: at.wrapping_add(confirm_timeout_us),
+                };
+                self.baud = baud;
+            }
+            Switch::Trial { previous, until } if is_due(until, now) => {
+                palantir.switch_baud(previous);
+                self.baud = previous;
+                self.switch = Switch::Steady;
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::PongData;
+    use crate::tests::{next_frame, MockBus, MockError};
+    use crate::Transaction;
+
+    struct Bus3 {
+        master: Palantir<MockBus>,
+        negotiator: BaudNegotiator,
+        slaves: Vec<(Palantir<MockBus>, BaudFollower)>,
+    }
+
+    /// A master and slaves 2 and 3, whose buses go up to the given rates.
+    fn bus(max_bauds: [u32; 3]) -> Bus3 {
+        let mut buses = MockBus::multidrop(3);
+        for (bus, max_baud) in buses.iter_mut().zip(max_bauds.iter()) {
+            bus.max_baud = *max_baud;
+        }
+        let mut buses = buses.into_iter();
+        let master = Palantir::discovered_master([2, 3, 0, 0, 0, 0, 0], buses.next().unwrap());
+        let mut negotiator = BaudNegotiator::new(master.slaves().unwrap());
+        negotiator.set_switch_delay(5_000);
+        negotiator.set_confirm_timeout(30_000);
+        let slaves = buses
+            .zip(2..)
+            .map(|(bus, address)| {
+                (
+                    Palantir::discovered_slave(address, bus),
+                    BaudFollower::new(),
+                )
+            })
+            .collect();
+        Bus3 {
+            master,
+            negotiator,
+            slaves,
+        }
+    }
+
+    impl Bus3 {
+        /// Runs everyone until `end`, except slaves once `stop` says so.
+        /// Frames a slave reads are lost if `drop` says so.
+        fn run(
+            &mut self,
+            end: Instant,
+            stop: impl Fn(&Palantir<MockBus>, Address) -> bool,
+            mut drop: impl FnMut(Address, &Frame) -> bool,
+        ) -> Vec<BaudEvent> {
+            let mut events = Vec::new();
+            for now in (0..end).step_by(100) {
+                for _ in 0..MAX_MESSAGE_LEN {
+                    match self.negotiator.poll(&mut self.master, now) {
+                        Ok(Some(event)) => events.push(event),
+                        Ok(None) | Err(Error::Bus(MockError::Framing)) => (),
+                        Err(e) => panic!("{:?}", e),
+                    }
+                    for (slave, follower) in self.slaves.iter_mut() {
+                        if stop(&self.master, slave.address()) {
+                            continue;
+                        }
+                        follower.check(slave, now);
+                        let frame = match slave.read() {
+                            Ok(frame) if !drop(slave.address(), &frame) => frame,
+                            Ok(_)
+                            | Err(nb::Error::WouldBlock)
+                            | Err(nb::Error::Other(Error::Bus(MockError::Framing))) => continue,
+                            Err(e) => panic!("{:?}", e),
+                        };
+                        match follower.handle(slave, frame, now) {
+                            Ok(None) => (),
+                            other => panic!("{:?}", other),
+                        }
+                    }
+                }
+            }
+            events
+        }
+
+        fn bauds(&self) -> Vec<u32> {
+            let slaves = self.slaves.iter().map(|(slave, _)| slave.bus.baud);
+            core::iter::once(self.master.bus.baud)
+                .chain(slaves)
+                .collect()
+        }
+    }
+
+    #[test]
+    fn switches_to_fastest_common_rate() {
+        let mut bus = bus([1_000_000, 115_200, 250_000]);
+        assert_eq!(
+            bus.run(50_000, |_, _| false, |_, _| false),
+            [BaudEvent::Switched(115_200)]
+        );
+        assert_eq!(bus.negotiator.baud(), 115_200);
+        assert_eq!(bus.bauds(), [115_200; 3]);
+
+        // Confirmed slaves stay put
+        bus.run(100_000, |_, _| false, |_, _| false);
+        assert_eq!(bus.slaves[0].1.baud(), 115_200);
+        assert_eq!(bus.bauds(), [115_200; 3]);
+    }
+
+    #[test]
+    fn falls_back_when_a_slave_goes_quiet() {
+        let mut bus = bus([1_000_000; 3]);
+        // Slave 3 dies the moment the master switches
+        let events = bus.run(
+            100_000,
+            |master, address| address == 3 && master.bus.baud != BAUD_RATES[0],
+            |_, _| false,
+        );
+        assert_eq!(events, [BaudEvent::FellBack(3)]);
+        assert_eq!(bus.negotiator.baud(), BAUD_RATES[0]);
+        assert_eq!(bus.master.bus.baud, BAUD_RATES[0]);
+        assert_eq!(bus.slaves[0].1.baud(), BAUD_RATES[0]);
+        assert_eq!(bus.slaves[0].0.bus.baud, BAUD_RATES[0]);
+    }
+
+    #[test]
+    fn lost_confirmations_are_sent_again() {
+        let mut bus = bus([1_000_000; 3]);
+        let mut dropped = false;
+        let events = bus.run(
+            100_000,
+            |_, _| false,
+            |address, frame| {
+                let drop = address == 3 && frame.message == Message::ConfirmBaud && !dropped;
+                dropped |= drop;
+                drop
+            },
+        );
+        assert!(dropped);
+        assert_eq!(events, [BaudEvent::Switched(1_000_000)]);
+        assert_eq!(bus.bauds(), [1_000_000; 3]);
+        assert_eq!(bus.slaves[1].1.baud(), 1_000_000);
+    }
+
+    #[test]
+    fn stays_put_without_a_faster_common_rate() {
+        let mut bus = bus([1_000_000, 1_000_000, BAUD_RATES[0]]);
+        assert_eq!(
+            bus.run(20_000, |_, _| false, |_, _| false),
+            [BaudEvent::Unchanged]
+        );
+        assert!(bus.negotiator.is_done());
+        assert_eq!(bus.bauds(), [BAUD_RATES[0]; 3]);
+    }
+
+    /// Answers the negotiator's next `GetBaudRates` with a pong.
+    fn answer_wrong(
+        negotiator: &mut BaudNegotiator,
+        master: &mut Palantir<MockBus>,
+        slave: &mut Palantir<MockBus>,
+    ) {
+        let mut request = None;
+        for _ in 0..MAX_MESSAGE_LEN {
+            assert_eq!(negotiator.poll(master, 0), Ok(None));
+            request = next_frame(slave);
+            if request.is_some() {
+                break;
+            }
+        }
+        let id = match request {
+            Some(Frame {
+                transaction: Some(Transaction::Request(id)),
+                message: Message::GetBaudRates,
+                ..
+            }) => id,
+            other => panic!("expected a request, got {:?}", other),
+        };
+        let pong = Message::Pong(PongData::new(2, 0));
+        slave.respond(MASTER_ADDRESS, id, &pong).unwrap();
+    }
+
+    #[test]
+    fn wrong_answers_are_asked_again() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::discovered_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::discovered_slave(2, slave_bus);
+        let mut negotiator = BaudNegotiator::new(master.slaves().unwrap());
+
+        for _ in 0..MAX_QUERY_ATTEMPTS - 1 {
+            answer_wrong(&mut negotiator, &mut master, &mut slave);
+        }
+        assert!(!negotiator.is_done());
+
+        // The last wrong answer ends negotiation at the boot rate
+        answer_wrong(&mut negotiator, &mut master, &mut slave);
+        let mut event = None;
+        for _ in 0..MAX_MESSAGE_LEN {
+            event = negotiator.poll(&mut master, 0).unwrap();
+            if event.is_some() {
+                break;
+            }
+        }
+        assert_eq!(event, Some(BaudEvent::Unchanged));
+        assert!(negotiator.is_done());
+        assert_eq!(master.bus.baud, BAUD_RATES[0]);
+    }
+}
@@ -0,0 +1,430 @@
+//! Recording and playback of raw bus traffic.
+//!
+//! A capture is a flat byte buffer:
+//!
+//! ```text
+//! offset  size  field
+//! 0       4     magic, b"PLNT"
+//! 4       1     format version, currently 1
+//! 5       3     reserved, zero
+//! 8       6*n   records
+//! ```
+//!
+//! Each record is a little endian `u32` timestamp in microseconds followed by the
+//! little endian `u16` bus word exactly as `Bus::read` returned it, so bit 8 still
+//! marks address words. Timestamps come from whatever `Clock` did the recording
+//! and may wrap; only the difference between consecutive records matters.
+
+use crate::time::{elapsed, Clock, Instant};
+use crate::Bus;
+
+pub const CAPTURE_MAGIC: [u8; 4] = *b"PLNT";
+pub const CAPTURE_VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 8;
+pub const RECORD_LEN: usize = 6;
+
+/// Link type used for pcapng exports (`LINKTYPE_USER0`). Each packet holds one
+/// little endian bus word.
+pub const PCAPNG_LINKTYPE: u16 = 147;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Record {
+    pub timestamp: Instant,
+    pub word: u16,
+}
+
+impl Record {
+    pub fn from_slice(data: &[u8]) -> Option<Self> {
+        if data.len() < RECORD_LEN {
+            return None;
+        }
+        Some(Record {
+            timestamp: u32::from_le_bytes([data[0], data[1], data[2], data[3]]),
+            word: u16::from_le_bytes([data[4], data[5]]),
+        })
+    }
+
+    pub fn to_array(&self) -> [u8; RECORD_LEN] {
+        let mut ret = [0u8; RECORD_LEN];
+        ret[..4].copy_from_slice(&self.timestamp.to_le_bytes());
+        ret[4..].copy_from_slice(&self.word.to_le_bytes());
+        ret
+    }
+}
+
+/// Writes a capture into a caller supplied buffer. Records that don't fit are
+/// counted and dropped rather than interrupting the bus.
+#[derive(Debug)]
+pub struct Recorder<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    dropped: u32,
+}
+
+impl<'a> Recorder<'a> {
+    /// `buf` must be at least `HEADER_LEN` bytes long.
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        buf[..4].copy_from_slice(&CAPTURE_MAGIC);
+        buf[4] = CAPTURE_VERSION;
+        buf[5..HEADER_LEN].copy_from_slice(&[0; 3]);
+        Recorder {
+            buf,
+            len: HEADER_LEN,
+            dropped: 0,
+        }
+    }
+
+    pub fn record(&mut self, record: Record) {
+        if self.len + RECORD_LEN > self.buf.len() {
+            self.dropped = self.dropped.saturating_add(1);
+            return;
+        }
+        self.buf[self.len..self.len + RECORD_LEN].copy_from_slice(&record.to_array());
+        self.len += RECORD_LEN;
+    }
+
+    /// Number of records that didn't fit in the buffer.
+    pub fn dropped(&self) -> u32 {
+        self.dropped
+    }
+
+    /// The capture recorded so far, header included.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+
+    pub fn capture(&self) -> Capture<'_> {
+        Capture {
+            records: &self.buf[HEADER_LEN..self.len],
+        }
+    }
+}
+
+/// A read-only view of a capture.
+#[derive(Clone, Copy, Debug)]
+pub struct Capture<'a> {
+    records: &'a [u8],
+}
+
+impl<'a> Capture<'a> {
+    /// Checks the header and returns `None` if `data` isn't a capture this
+    /// version understands. A trailing partial record is ignored.
+    pub fn from_slice(data: &'a [u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN || data[..4] != CAPTURE_MAGIC || data[4] != CAPTURE_VERSION {
+            return None;
+        }
+        let records = &data[HEADER_LEN..];
+        let whole = records.len() - records.len() % RECORD_LEN;
+        Some(Capture {
+            records: &records[..whole],
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len() / RECORD_LEN
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn records(&self) -> Records<'a> {
+        Records {
+            chunks: self.records.chunks_exact(RECORD_LEN),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Records<'a> {
+    chunks: core::slice::ChunksExact<'a, u8>,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Record> {
+        self.chunks.next().and_then(Record::from_slice)
+    }
+}
+
+/// Wraps a `Bus` and records every word read from it.
+#[derive(Debug)]
+pub struct RecordingBus<'a, B: Bus, C: Clock> {
+    bus: B,
+    clock: C,
+    recorder: Recorder<'a>,
+}
+
+impl<'a, B: Bus, C: Clock> RecordingBus<'a, B, C> {
+    pub fn new(bus: B, clock: C, recorder: Recorder<'a>) -> Self {
+        RecordingBus {
+            bus,
+            clock,
+            recorder,
+        }
+    }
+
+    pub fn recorder(&self) -> &Recorder<'a> {
+        &self.recorder
+    }
+
+    pub fn free(self) -> (B, C, Recorder<'a>) {
+        (self.bus, self.clock, self.recorder)
+    }
+}
+
+impl<'a, B: Bus, C: Clock> Bus for RecordingBus<'a, B, C> {
+    type Error = B::Error;
+
+    fn send(&mut self, data: &[u16]) -> Result<(), Self::Error> {
+        self.bus.send(data)
+    }
+
+    fn read(&mut self) -> nb::Result<u16, Self::Error> {
+        let word = self.bus.read()?;
+        self.recorder.record(Record {
+            timestamp: self.clock.now(),
+            word,
+        });
+        Ok(word)
+    }
+}
+
+/// A `Bus` that plays a capture back with its original timing. Anything sent on
+/// it is discarded.
+#[derive(Debug)]
+pub struct ReplayBus<'a, C: Clock> {
+    records: Records<'a>,
+    next: Option<Record>,
+    clock: C,
+    /// Local time and capture time of the first record, set on the first read.
+    origin: Option<(Instant, Instant)>,
+}
+
+impl<'a, C: Clock> ReplayBus<'a, C> {
+    pub fn new(capture: Capture<'a>, clock: C) -> Self {
+        let mut records = capture.records();
+        let next = records.next();
+        ReplayBus {
+            records,
+            next,
+            clock,
+            origin: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next.is_none()
+    }
+}
+
+impl<'a, C: Clock> Bus for ReplayBus<'a, C> {
+    type Error = ();
+
+    fn send(&mut self, _data: &[u16]) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn read(&mut self) -> nb::Result<u16, Self::Error> {
+        let record = match self.next {
+            Some(record) => record,
+            None => return Err(nb::Error::WouldBlock),
+        };
+        let now = self.clock.now();
+        let (local, captured) = *self.origin.get_or_insert((now, record.timestamp));
+
+        if elapsed(local, now) < elapsed(captured, record.timestamp) {
+            return Err(nb::Error::WouldBlock);
+        }
+        self.next = self.records.next();
+        Ok(record.word)
+    }
+}
+
+#[cfg(feature = "std")]
+pub mod pcapng {
+    //! Export of captures as pcapng, one Enhanced Packet Block per bus word on a
+    //! single interface with link type `PCAPNG_LINKTYPE` and microsecond
+    //! timestamps.
+
+    use super::{Capture, PCAPNG_LINKTYPE};
+    use std::io::{Result, Write};
+
+    const SECTION_HEADER: u32 = 0x0A0D_0D0A;
+    const INTERFACE_DESCRIPTION: u32 = 0x0000_0001;
+    const ENHANCED_PACKET: u32 = 0x0000_0006;
+    const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+
+    fn block<W: Write>(out: &mut W, block_type: u32, body: &[u8]) -> Result<()> {
+        let total = (12 + body.len()) as u32;
+        out.write_all(&block_type.to_le_bytes())?;
+        out.write_all(&total.to_le_bytes())?;
+        out.write_all(body)?;
+        out.write_all(&total.to_le_bytes())
+    }
+
+    pub fn write<W: Write>(capture: &Capture, out: &mut W) -> Result<()> {
+        let mut shb = Vec::with_capacity(16);
+        shb.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+        shb.extend_from_slice(&1u16.to_le_bytes());
+        shb.extend_from_slice(&0u16.to_le_bytes());
+        shb.extend_from_slice(&(-1i64).to_le_bytes());
+        block(out, SECTION_HEADER, &shb)?;
+
+        let mut idb = Vec::with_capacity(8);
+        idb.extend_from_slice(&PCAPNG_LINKTYPE.to_le_bytes());
+        idb.extend_from_slice(&0u16.to_le_bytes());
+        idb.extend_from_slice(&2u32.to_le_bytes());
+        block(out, INTERFACE_DESCRIPTION, &idb)?;
+
+        // Capture timestamps are 32 bits and wrap, pcapng wants 64.
+        let mut timestamp = 0u64;
+        let mut previous = None;
+        for record in capture.records() {
+            if let Some(previous) = previous {
+                timestamp += u64::from(record.timestamp.wrapping_sub(previous));
+            } else {
+                timestamp = u64::from(record.timestamp);
+            }
+            previous = Some(record.timestamp);
+
+            let mut epb = Vec::with_capacity(24);
+            epb.extend_from_slice(&0u32.to_le_bytes());
+            epb.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+            epb.extend_from_slice(&(timestamp as u32).to_le_bytes());
+            epb.extend_from_slice(&2u32.to_le_bytes());
+            epb.extend_from_slice(&2u32.to_le_bytes());
+            epb.extend_from_slice(&record.word.to_le_bytes());
+            // Pad packet data to 32 bits
+            epb.extend_from_slice(&[0, 0]);
+            block(out, ENHANCED_PACKET, &epb)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{DiscoveryRequestData, Message, Palantir, MAX_MESSAGE_LEN};
+    use core::cell::Cell;
+
+    struct MockClock(Cell<Instant>);
+
+    impl Clock for MockClock {
+        fn now(&self) -> Instant {
+            self.0.get()
+        }
+    }
+
+    /// A discovery request for node 2 in the original frame format.
+    fn sample<'a>(buf: &'a mut [u8]) -> Recorder<'a> {
+        let mut recorder = Recorder::new(buf);
+        recorder.record(Record {
+            timestamp: 100,
+            word: 0x102,
+        });
+        recorder.record(Record {
+            timestamp: 1100,
+            word: 2,
+        });
+        recorder.record(Record {
+            timestamp: 2100,
+            word: 0,
+        });
+        recorder.record(Record {
+            timestamp: 3100,
+            word: 2,
+        });
+        recorder
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let mut buf = [0u8; HEADER_LEN + 4 * RECORD_LEN];
+        let recorder = sample(&mut buf);
+        assert_eq!(recorder.dropped(), 0);
+
+        let capture = Capture::from_slice(recorder.as_bytes()).unwrap();
+        assert_eq!(capture.len(), 4);
+        let words: Vec<u16> = capture.records().map(|r| r.word).collect();
+        assert_eq!(words, [0x102, 2, 0, 2]);
+    }
+
+    #[test]
+    fn recorder_drops_when_full() {
+        let mut buf = [0u8; HEADER_LEN + 2 * RECORD_LEN];
+        let recorder = sample(&mut buf);
+        assert_eq!(recorder.dropped(), 2);
+        assert_eq!(recorder.capture().len(), 2);
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        assert!(Capture::from_slice(b"PLNX\x01\0\0\0").is_none());
+        assert!(Capture::from_slice(b"PLNT\x02\0\0\0").is_none());
+        assert!(Capture::from_slice(b"PLNT").is_none());
+    }
+
+    #[test]
+    fn replay_keeps_timing() {
+        let mut buf = [0u8; HEADER_LEN + 4 * RECORD_LEN];
+        let recorder = sample(&mut buf);
+        let clock = MockClock(Cell::new(5000));
+        let mut bus = ReplayBus::new(recorder.capture(), &clock);
+
+        assert_eq!(bus.read(), Ok(0x102));
+        assert_eq!(bus.read(), Err(nb::Error::WouldBlock));
+        clock.0.set(5999);
+        assert_eq!(bus.read(), Err(nb::Error::WouldBlock));
+        clock.0.set(6000);
+        assert_eq!(bus.read(), Ok(2));
+        clock.0.set(9000);
+        assert_eq!(bus.read(), Ok(0));
+        assert_eq!(bus.read(), Ok(2));
+        assert!(bus.is_finished());
+    }
+
+    #[test]
+    fn replay_into_palantir() {
+        let mut buf = [0u8; HEADER_LEN + 4 * RECORD_LEN];
+        let recorder = sample(&mut buf);
+        let clock = MockClock(Cell::new(0));
+        let mut palantir = Palantir::new_slave(2, ReplayBus::new(recorder.capture(), &clock));
+
+        let mut msg: Option<Message> = None;
+        for tick in 0..MAX_MESSAGE_LEN as u32 {
+            clock.0.set(tick * 1000);
+            msg = palantir.poll();
+            if msg.is_some() {
+                break;
+            }
+        }
+        match msg {
+            Some(Message::DiscoveryRequest(data)) => {
+                assert_eq!(
+                    data.target_address(),
+                    DiscoveryRequestData::new(2).target_address()
+                )
+            }
+            _ => panic!("replayed frame was not parsed"),
+        }
+    }
+
+    #[test]
+    fn pcapng_layout() {
+        let mut buf = [0u8; HEADER_LEN + 4 * RECORD_LEN];
+        let recorder = sample(&mut buf);
+        let mut out = Vec::new();
+        pcapng::write(&recorder.capture(), &mut out).unwrap();
+
+        // Section header, interface description and one packet block per word
+        assert_eq!(out.len(), 28 + 20 + 4 * 36);
+        assert_eq!(out[..4], [0x0A, 0x0D, 0x0D, 0x0A]);
+        assert_eq!(out[36..38], PCAPNG_LINKTYPE.to_le_bytes());
+        // First packet's data
+        assert_eq!(out[48 + 28..48 + 30], 0x102u16.to_le_bytes());
+    }
+}
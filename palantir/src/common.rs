@@ -1,8 +1,54 @@
 pub type Address = u8;
-pub type SlaveAddresses = [Address; 7];
+pub const MAX_SLAVES: usize = 7;
+pub type SlaveAddresses = [Address; MAX_SLAVES];
 
 pub const MASTER_ADDRESS: Address = 1;
+/// Frames sent here reach every node. Nodes from before protocol version 2
+/// ignore them.
+pub const BROADCAST_ADDRESS: Address = 0xFF;
+
+/// Version of the wire format this crate speaks. Exchanged during discovery
+/// and reported in `DeviceInfo`.
+pub const PROTOCOL_VERSION: u8 = 2;
+/// The frame format of the first firmware, before versions were exchanged:
+/// the message follows the length word directly, with no transaction byte and
+/// no CRC. Assumed for any peer that hasn't said otherwise.
+pub const BASE_PROTOCOL_VERSION: u8 = 1;
+
+/// What a protocol version adds to the wire format.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Features {
+    /// Frames end in a CRC-16 over everything before it. Since version 2.
+    pub crc: bool,
+    /// A transaction byte comes before the message. Since version 2.
+    pub transactions: bool,
+}
+
+impl Features {
+    pub fn of(version: u8) -> Self {
+        Features {
+            crc: version >= 2,
+            transactions: version >= 2,
+        }
+    }
+}
 
 /// This is the maximum message length including address and crc bytes.
 pub const MAX_MESSAGE_LEN: usize = 64;
-pub const MAX_DATA_LEN: usize = MAX_MESSAGE_LEN - 2;
+/// Room for the transaction byte and the message, after the address, the
+/// length and two CRC words. Small enough to leave the flags in the top bits
+/// of the length word free.
+pub const MAX_DATA_LEN: usize = MAX_MESSAGE_LEN - 4;
+
+/// Received frames `Palantir` can hold unless created with `with_rx_queue`.
+pub const DEFAULT_RX_QUEUE_LEN: usize = 8;
+/// Frames each transmit priority class can hold.
+pub const TX_QUEUE_LEN: usize = 4;
+
+/// How many times `Palantir::send` tries to get a frame through a busy bus
+/// before giving up with `Error::Collision`.
+pub const MAX_SEND_ATTEMPTS: u32 = 5;
+/// Upper bound of the random backoff after the first collision. Doubles with
+/// every further attempt.
+pub const BACKOFF_SLOT_US: u32 = 2_000;
@@ -0,0 +1,333 @@
+//! What each node is and can do.
+//!
+//! A node describes itself with `Palantir::set_device_info`, after which
+//! `GetDeviceInfo` is answered by `Palantir::read` like `GetStats`. The
+//! master collects the answers in an `Inventory` and checks them against
+//! the `Requirements` of the game before starting it.
+
+use crate::common::*;
+use crate::error::{CodecError, Error};
+use crate::messages::{Frame, Message, MessageKind};
+use crate::time::Instant;
+use crate::transaction::{Sequencer, Step};
+use crate::{Bus, Palantir};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeviceInfo {
+    /// Filled in by `Palantir` when answering.
+    pub address: Address,
+    /// Board type, numbered by the application.
+    pub board: u16,
+    pub hardware_revision: u8,
+    pub firmware_version: Version,
+    /// First 4 bytes of the firmware's commit hash.
+    pub git_hash: u32,
+    pub protocol_version: u8,
+    /// Bit per `MessageKind` the node handles, see `MessageKind::bit`.
+    pub messages: u64,
+    pub switches: u16,
+    pub coils: u16,
+    pub lamps: u16,
+}
+
+impl DeviceInfo {
+    pub const LEN: usize = 26;
+
+    pub fn supports(&self, kind: MessageKind) -> bool {
+        self.messages & kind.bit() != 0
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, CodecError> {
+        if data.len() < Self::LEN {
+            return Err(CodecError::Truncated {
+                expected: Self::LEN,
+                got: data.len(),
+            });
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let mut messages = [0u8; 8];
+        messages.copy_from_slice(&data[12..20]);
+        Ok(DeviceInfo {
+            address: data[0],
+            board: u16_at(1),
+            hardware_revision: data[3],
+            firmware_version: Version {
+                major: data[4],
+                minor: data[5],
+                patch: data[6],
+            },
+            git_hash: u32::from_le_bytes([data[7], data[8], data[9], data[10]]),
+            protocol_version: data[11],
+            messages: u64::from_le_bytes(messages),
+            switches: u16_at(20),
+            coils: u16_at(22),
+            lamps: u16_at(24),
+        })
+    }
+
+    pub fn to_array(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[0] = self.address;
+        data[1..3].copy_from_slice(&self.board.to_le_bytes());
+        data[3] = self.hardware_revision;
+        let version = self.firmware_version;
+        data[4..7].copy_from_slice(&[version.major, version.minor, version.patch]);
+        data[7..11].copy_from_slice(&self.git_hash.to_le_bytes());
+        data[11] = self.protocol_version;
+        data[12..20].copy_from_slice(&self.messages.to_le_bytes());
+        data[20..22].copy_from_slice(&self.switches.to_le_bytes());
+        data[22..24].copy_from_slice(&self.coils.to_le_bytes());
+        data[24..26].copy_from_slice(&self.lamps.to_le_bytes());
+        data
+    }
+}
+
+/// What a game needs from the cabinet.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Requirements {
+    /// Lowest protocol version every slave has to speak.
+    pub protocol_version: u8,
+    /// Messages every slave has to handle, see `MessageKind::bit`.
+    pub messages: u64,
+    /// Totals across all slaves.
+    pub switches: u16,
+    pub coils: u16,
+    pub lamps: u16,
+}
+
+/// Why the cabinet doesn't meet the `Requirements`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Missing {
+    /// The slave never described itself.
+    NoInfo(Address),
+    ProtocolVersion(Address),
+    /// Bits of the required messages the slave doesn't handle.
+    Messages(Address, u64),
+    Switches,
+    Coils,
+    Lamps,
+}
+
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum InventoryEvent {
+    /// The slave described itself.
+    Described(Address),
+    /// The slave didn't answer `GetDeviceInfo` in time.
+    NoAnswer(Address),
+    /// Came in while taking inventory, for the application to handle.
+    Frame(Frame),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    address: Address,
+    info: Option<DeviceInfo>,
+}
+
+/// Master side: asks every slave for its `DeviceInfo`.
+#[derive(Debug)]
+pub struct Inventory {
+    entries: [Option<Entry>; MAX_SLAVES],
+    /// Index into `entries` of the next slave to ask.
+    next: usize,
+    requests: Sequencer,
+}
+
+impl Inventory {
+    pub fn new(slaves: &SlaveAddresses) -> Self {
+        let mut entries = [None; MAX_SLAVES];
+        for (entry, address) in entries.iter_mut().zip(slaves.iter()) {
+            if *address != 0 {
+                *entry = Some(Entry {
+                    address: *address,
+                    info: None,
+                });
+            }
+        }
+        Inventory {
+            entries,
+            next: 0,
+            requests: Sequencer::default(),
+        }
+    }
+
+    /// Whether every slave has been asked, answered or not.
+    pub fn is_complete(&self) -> bool {
+        self.entries[self.next..].iter().all(Option::is_none)
+    }
+
+    /// `None` until `address` has described itself.
+    pub fn get(&self, address: Address) -> Option<&DeviceInfo> {
+        self.entries
+            .iter()
+            .flatten()
+            .find(|entry| entry.address == address)
+            .and_then(|entry| entry.info.as_ref())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DeviceInfo> {
+        self.entries
+            .iter()
+            .flatten()
+            .filter_map(|entry| entry.info.as_ref())
+    }
+
+    /// Asks the slaves again, e.g. after one was replaced.
+    pub fn refresh(&mut self) {
+        self.next = 0;
+        self.requests.reset();
+    }
+
+    /// Asks the slaves one at a time, moving on once one answers or times
+    /// out. Doesn't block, so it can run alongside the rest of the boot.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<InventoryEvent>, Error<B::Error>> {
+        while self.next < MAX_SLAVES && self.entries[self.next].is_none() {
+            self.next += 1;
+        }
+        let entry = self.entries.get_mut(self.next).and_then(Option::as_mut);
+        let request = entry
+            .as_ref()
+            .map(|entry| (entry.address, Message::GetDeviceInfo));
+        let response = match self.requests.poll(palantir, request, now)? {
+            Step::Frame(frame) => return Ok(Some(InventoryEvent::Frame(frame))),
+            Step::Waiting => return Ok(None),
+            Step::Done(response) => response,
+        };
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let event = match response {
+            Some(Message::DeviceInfo(info)) => {
+                entry.info = Some(info);
+                InventoryEvent::Described(entry.address)
+            }
+            _ => {
+                entry.info = None;
+                InventoryEvent::NoAnswer(entry.address)
+            }
+        };
+        self.next += 1;
+        Ok(Some(event))
+    }
+
+    /// Checks every slave described itself and that together they meet
+    /// `requirements`. Reports the first shortfall found.
+    pub fn check(&self, requirements: &Requirements) -> Result<(), Missing> {
+        let (mut switches, mut coils, mut lamps) = (0u32, 0u32, 0u32);
+        for entry in self.entries.iter().flatten() {
+            let info = entry.info.ok_or(Missing::NoInfo(entry.address))?;
+            if info.protocol_version < requirements.protocol_version {
+                return Err(Missing::ProtocolVersion(entry.address));
+            }
+            let messages = requirements.messages & !info.messages;
+            if messages != 0 {
+                return Err(Missing::Messages(entry.address, messages));
+            }
+            switches += info.switches as u32;
+            coils += info.coils as u32;
+            lamps += info.lamps as u32;
+        }
+        if switches < requirements.switches as u32 {
+            return Err(Missing::Switches);
+        }
+        if coils < requirements.coils as u32 {
+            return Err(Missing::Coils);
+        }
+        if lamps < requirements.lamps as u32 {
+            return Err(Missing::Lamps);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::MockBus;
+
+    fn info(switches: u16, messages: &[MessageKind]) -> DeviceInfo {
+        DeviceInfo {
+            board: 0x51,
+            protocol_version: PROTOCOL_VERSION,
+            messages: messages.iter().fold(0, |bits, kind| bits | kind.bit()),
+            switches,
+            coils: 4,
+            ..DeviceInfo::default()
+        }
+    }
+
+    #[test]
+    fn builds_inventory_and_checks_requirements() {
+        let mut buses = MockBus::multidrop(3).into_iter();
+        let mut master = Palantir::discovered_master([2, 3, 0, 0, 0, 0, 0], buses.next().unwrap());
+        let mut two = Palantir::discovered_slave(2, buses.next().unwrap());
+        let mut three = Palantir::discovered_slave(3, buses.next().unwrap());
+        two.set_device_info(info(16, &[MessageKind::Poll, MessageKind::Ping]));
+        three.set_device_info(info(8, &[MessageKind::Poll]));
+
+        let mut inventory = Inventory::new(master.slaves().unwrap());
+        let mut events = Vec::new();
+        while !inventory.is_complete() {
+            if let Some(event) = inventory.poll(&mut master, 0).unwrap() {
+                events.push(event);
+            }
+            assert_eq!(two.poll(), None);
+            assert_eq!(three.poll(), None);
+        }
+        assert_eq!(
+            events,
+            [InventoryEvent::Described(2), InventoryEvent::Described(3)]
+        );
+        assert_eq!(inventory.get(3).map(|info| info.address), Some(3));
+        assert_eq!(inventory.iter().count(), 2);
+
+        let mut requirements = Requirements {
+            protocol_version: PROTOCOL_VERSION,
+            messages: MessageKind::Poll.bit(),
+            switches: 24,
+            coils: 8,
+            lamps: 0,
+        };
+        assert_eq!(inventory.check(&requirements), Ok(()));
+        requirements.lamps = 1;
+        assert_eq!(inventory.check(&requirements), Err(Missing::Lamps));
+        requirements.messages |= MessageKind::Ping.bit();
+        assert_eq!(
+            inventory.check(&requirements),
+            Err(Missing::Messages(3, MessageKind::Ping.bit()))
+        );
+    }
+
+    #[test]
+    fn silent_slave_fails_the_check() {
+        let (master_bus, _slave_bus) = MockBus::pair();
+        let mut master = Palantir::discovered_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut inventory = Inventory::new(master.slaves().unwrap());
+
+        assert_eq!(inventory.poll(&mut master, 0), Ok(None));
+        let event = inventory.poll(&mut master, crate::DEFAULT_REQUEST_TIMEOUT_US);
+        assert_eq!(event, Ok(Some(InventoryEvent::NoAnswer(2))));
+        assert!(inventory.is_complete());
+        assert_eq!(
+            inventory.check(&Requirements::default()),
+            Err(Missing::NoInfo(2))
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use crate::common::Address;
+
+/// Errors from turning a `Message` into bytes or back.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CodecError {
+    /// The message doesn't fit in the space available for it.
+    BufferTooSmall,
+    UnknownMessageId(u8),
+    /// The data ended before the message did.
+    Truncated {
+        expected: usize,
+        got: usize,
+    },
+    /// The frame's length byte is zero or more than `MAX_DATA_LEN`.
+    InvalidLength(u8),
+    /// The frame's CRC doesn't match its contents.
+    CrcMismatch,
+    /// A field holds a value no version of the protocol uses.
+    InvalidField,
+}
+
+/// Receive errors a UART can report, counted in `Stats`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LineError {
+    Framing,
+    Parity,
+    /// A word arrived before the previous one was read.
+    Overrun,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error<E> {
+    NotMaster,
+    SendToSelf,
+    InvalidDiscoveryAck,
+    /// Slave received a different message when it was anticipating a discovery request.
+    InvalidDiscoveryReq,
+    /// Monitor-mode nodes only listen and never transmit.
+    MonitorMode,
+    /// A frame couldn't be encoded or decoded.
+    Codec(CodecError),
+    /// The bus can't tell the time, see `Bus::timestamp`.
+    NoTimestamp,
+    /// Nothing arrived in the time allowed.
+    Timeout,
+    /// Another node was transmitting at the same time.
+    Collision,
+    /// The slave at this address didn't answer discovery.
+    NotDiscovered(Address),
+    /// Only the node holding the token may transmit in token-passing mode.
+    NotTokenHolder,
+    /// Every slot for outstanding requests is taken.
+    TooManyRequests,
+    /// The request was already answered or has timed out.
+    UnknownTransaction,
+    /// The transmit queue for this priority is full.
+    TxQueueFull,
+    /// The underlying `Bus` failed.
+    Bus(E),
+}
+
+impl<E> From<CodecError> for Error<E> {
+    fn from(error: CodecError) -> Self {
+        Error::Codec(error)
+    }
+}
+
+#[cfg(feature = "std")]
+mod std_impls {
+    use super::{CodecError, Error};
+    use std::fmt;
+
+    impl fmt::Display for CodecError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                CodecError::BufferTooSmall => write!(f, "buffer too small for message"),
+                CodecError::UnknownMessageId(id) => write!(f, "unknown message id {}", id),
+                CodecError::Truncated { expected, got } => {
+                    write!(
+                        f,
+                        "message truncated, expected {} bytes, got {}",
+                        expected, got
+                    )
+                }
+                CodecError::InvalidLength(len) => write!(f, "invalid frame length {}", len),
+                CodecError::CrcMismatch => write!(f, "CRC mismatch"),
+                CodecError::InvalidField => write!(f, "invalid field value"),
+            }
+        }
+    }
+
+    impl std::error::Error for CodecError {}
+
+    impl<E: fmt::Debug> fmt::Display for Error<E> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Error::NotMaster => write!(f, "only the master can do that"),
+                Error::SendToSelf => write!(f, "cannot send a message to own address"),
+                Error::InvalidDiscoveryAck => write!(f, "expected a discovery acknowledge"),
+                Error::InvalidDiscoveryReq => write!(f, "expected a discovery request"),
+                Error::MonitorMode => write!(f, "monitor-mode nodes cannot transmit"),
+                Error::Codec(error) => write!(f, "codec error: {}", error),
+                Error::NoTimestamp => write!(f, "bus has no timestamps"),
+                Error::Timeout => write!(f, "timed out"),
+                Error::Collision => write!(f, "bus collision"),
+                Error::NotDiscovered(address) => write!(f, "slave {} was not discovered", address),
+                Error::NotTokenHolder => write!(f, "node does not hold the token"),
+                Error::TooManyRequests => write!(f, "too many outstanding requests"),
+                Error::UnknownTransaction => write!(f, "no such pending request"),
+                Error::TxQueueFull => write!(f, "transmit queue full"),
+                Error::Bus(error) => write!(f, "bus error: {:?}", error),
+            }
+        }
+    }
+
+    impl<E: fmt::Debug> std::error::Error for Error<E> {
+        fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+            match self {
+                Error::Codec(error) => Some(error),
+                _ => None,
+            }
+        }
+    }
+}
@@ -1,40 +1,135 @@
-use crate::Bus;
+use crate::uart::{BaudMode, StopBits, UartConfig};
+use crate::{Bus, LineError};
+use core::ops::Deref;
 use embedded_hal::{blocking::serial::write::Default, digital::v2::OutputPin, serial};
 use feather_m0 as hal;
 use hal::{
-    clock::{GenericClockController, Sercom0CoreClock},
+    clock::{
+        GenericClockController, Sercom0CoreClock, Sercom1CoreClock, Sercom2CoreClock,
+        Sercom3CoreClock, Sercom4CoreClock, Sercom5CoreClock,
+    },
     gpio::{Floating, Input, Pa10, Pa11, PfC, Port},
-    pac::{sercom0::USART, PM, SERCOM0},
+    pac::{
+        sercom0::{RegisterBlock, USART},
+        PM, SERCOM0, SERCOM1, SERCOM2, SERCOM3, SERCOM4, SERCOM5,
+    },
     prelude::*,
-    sercom::{PadPin, RxpoTxpo, Sercom0Pad2, Sercom0Pad3, UART0Padout},
-    time::Hertz,
+    sercom::{
+        PadPin, RxpoTxpo, Sercom0Pad2, Sercom0Pad3, UART0Padout, UART1Padout, UART2Padout,
+        UART3Padout, UART4Padout, UART5Padout,
+    },
 };
 
-type Padout = UART0Padout<Sercom0Pad3<Pa11<PfC>>, Sercom0Pad2<Pa10<PfC>>, (), ()>;
+/// A SERCOM instance that can carry the bus.
+pub trait Sercom: Deref<Target = RegisterBlock> {
+    type CoreClock;
 
-pub struct UartBus<P: OutputPin> {
-    padout: Padout,
-    sercom: SERCOM0,
+    fn clock_hz(clock: &Self::CoreClock) -> u32;
+    fn enable_apb_clock(pm: &mut PM);
+}
+
+/// Pad mappings that route SERCOM `S` to RX and TX, i.e. the HAL's
+/// `UARTxPadout` built from that instance's pads.
+pub trait Padout<S>: RxpoTxpo {}
+
+/// Ties a SERCOM instance to its power mask bit, core clock and padout type.
+macro_rules! sercom {
+    ($($SERCOM:ident: ($Padout:ident, $powermask:ident, $CoreClock:ident),)+) => {
+        $(
+            impl Sercom for $SERCOM {
+                type CoreClock = $CoreClock;
+
+                fn clock_hz(clock: &$CoreClock) -> u32 {
+                    clock.freq().0
+                }
+
+                fn enable_apb_clock(pm: &mut PM) {
+                    pm.apbcmask.modify(|_, w| w.$powermask().set_bit());
+                }
+            }
+
+            impl<RX, TX, RTS, CTS> Padout<$SERCOM> for $Padout<RX, TX, RTS, CTS> where
+                $Padout<RX, TX, RTS, CTS>: RxpoTxpo
+            {
+            }
+        )+
+    };
+}
+
+sercom! {
+    SERCOM0: (UART0Padout, sercom0_, Sercom0CoreClock),
+    SERCOM1: (UART1Padout, sercom1_, Sercom1CoreClock),
+    SERCOM2: (UART2Padout, sercom2_, Sercom2CoreClock),
+    SERCOM3: (UART3Padout, sercom3_, Sercom3CoreClock),
+    SERCOM4: (UART4Padout, sercom4_, Sercom4CoreClock),
+    SERCOM5: (UART5Padout, sercom5_, Sercom5CoreClock),
+}
+
+/// The Feather's RX/TX pins, PA11 and PA10 on SERCOM0.
+pub type FeatherPadout = UART0Padout<Sercom0Pad3<Pa11<PfC>>, Sercom0Pad2<Pa10<PfC>>, (), ()>;
+pub type FeatherUartBus<P> = UartBus<SERCOM0, FeatherPadout, P>;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum UartError {
+    /// The word read back while transmitting wasn't the one written, or never
+    /// came back at all.
+    Collision,
+    /// A word arrived without a valid stop bit.
+    FramingError,
+    ParityError,
+}
+
+pub struct UartBus<S: Sercom, PADS: Padout<S>, P: OutputPin> {
+    padout: PADS,
+    sercom: S,
     transmit_enable: P,
+    collision_detection: bool,
+    /// Frequency of the SERCOM core clock.
+    clock_hz: u32,
+    config: UartConfig,
+    actual_baud: u32,
+    /// Core clock cycles per microsecond, for `delay_us`.
+    cycles_per_us: u32,
+    /// How long to spin waiting for the echo of a transmitted word.
+    echo_timeout: u32,
+    /// Overruns not yet passed on by `take_overruns`.
+    overruns: u32,
+}
+
+/// Only the settings, the peripherals and pins have nothing useful to show.
+impl<S: Sercom, PADS: Padout<S>, P: OutputPin> core::fmt::Debug for UartBus<S, PADS, P> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("UartBus")
+            .field("collision_detection", &self.collision_detection)
+            .field("clock_hz", &self.clock_hz)
+            .field("config", &self.config)
+            .field("actual_baud", &self.actual_baud)
+            .finish_non_exhaustive()
+    }
 }
 
-impl<P: OutputPin> UartBus<P> {
-    pub fn new<F: Into<Hertz>, T: Into<Padout>>(
-        clock: &Sercom0CoreClock,
-        freq: F,
-        sercom: SERCOM0,
+impl<S: Sercom, PADS: Padout<S>, P: OutputPin> UartBus<S, PADS, P> {
+    /// `padout` is a tuple of `SercomXPadY`s, as for the HAL's `UARTX`.
+    ///
+    /// # Panics
+    ///
+    /// If the baud rate in `config` can't be reached with `clock`.
+    pub fn new<T: Into<PADS>>(
+        clock: &S::CoreClock,
+        config: UartConfig,
+        sercom: S,
         pm: &mut PM,
         padout: T,
         mut transmit_enable: P,
-    ) -> UartBus<P>
+    ) -> Self
     where
-        Padout: RxpoTxpo,
         <P as embedded_hal::digital::v2::OutputPin>::Error: core::fmt::Debug,
     {
         let padout = padout.into();
         transmit_enable.set_low().unwrap();
 
-        pm.apbcmask.modify(|_, w| w.sercom0_().set_bit());
+        S::enable_apb_clock(pm);
 
         // Lots of union fields which require unsafe access
         unsafe {
@@ -54,55 +149,115 @@ impl<P: OutputPin> UartBus<P> {
                 w.rxpo().bits(rxpo);
                 w.txpo().bits(txpo);
 
-                w.form().bits(0x00);
-                w.sampr().bits(0x00); // 16x oversample fractional
                 w.runstdby().set_bit(); // Run in standby
                 w.form().bits(0); // 0 is no parity bits
 
                 w.mode().usart_int_clk() // Internal clock mode
             });
+        }
 
-            // Calculate value for BAUD register
-            let sample_rate: u8 = 16;
-            let fref = clock.freq().0;
+        let mut bus = Self {
+            padout,
+            sercom,
+            transmit_enable,
+            collision_detection: false,
+            clock_hz: S::clock_hz(clock),
+            config,
+            actual_baud: 0,
+            // The sercom core clock is assumed to run at the CPU clock, as it
+            // does with `easy_new`.
+            cycles_per_us: S::clock_hz(clock) / 1_000_000,
+            echo_timeout: 0,
+            overruns: 0,
+        };
+        if bus.configure(&config).is_none() {
+            panic!("baud rate out of reach");
+        }
+        bus
+    }
 
-            //          TODO: Support fractional BAUD mode
-            //            let mul_ratio = (fref.0 * 1000) / (freq.into().0 * 16);
-            //
-            //            let baud = mul_ratio / 1000;
-            //            let fp = ((mul_ratio - (baud*1000))*8)/1000;
-            //
-            //            sercom.usart().baud()_frac_mode.modify(|_, w| {
-            //                w.baud().bits(baud as u16);
-            //                w.fp().bits(fp as u8)
-            //            });
+    /// Changes the line settings on the fly. Anything being sent or received
+    /// is lost. Returns the rate actually achieved, or `None`, leaving the
+    /// settings alone, if `config` can't be reached with this SERCOM's clock.
+    pub fn configure(&mut self, config: &UartConfig) -> Option<u32> {
+        let setting = config.baud_setting(self.clock_hz)?;
+        let usart = self.sercom.usart();
 
-            // Asynchronous arithmetic mode (Table 24-2 in datasheet)
-            let baud = calculate_baud_value(freq.into().0, fref, sample_rate);
+        // CTRLA, CTRLB and BAUD are enable-protected
+        usart.ctrla.modify(|_, w| w.enable().clear_bit());
+        while usart.syncbusy.read().enable().bit_is_set() {}
 
-            sercom.usart().baud().modify(|_, w| w.baud().bits(baud));
+        unsafe {
+            usart.ctrla.modify(|_, w| w.sampr().bits(setting.sampr));
+            match setting.mode {
+                BaudMode::Fractional => usart.baud_frac_mode().write(|w| {
+                    w.baud().bits(setting.baud);
+                    w.fp().bits(setting.fp)
+                }),
+                _ => usart.baud().write(|w| w.baud().bits(setting.baud)),
+            }
 
-            sercom.usart().ctrlb.modify(|_, w| {
-                w.sbmode().clear_bit(); // 0 is one stop bit see sec 25.8.2
+            usart.ctrlb.modify(|_, w| {
+                // See sec 25.8.2
+                match config.stop_bits {
+                    StopBits::One => w.sbmode().clear_bit(),
+                    StopBits::Two => w.sbmode().set_bit(),
+                };
                 w.chsize().bits(0x1); // 0x1 is 9 bit mode
                 w.txen().set_bit();
                 w.rxen().set_bit()
             });
+        }
+        while usart.syncbusy.read().ctrlb().bit_is_set() {}
 
-            while sercom.usart().syncbusy.read().ctrlb().bit_is_set() {}
+        usart.ctrla.modify(|_, w| w.enable().set_bit());
+        // wait for sync of ENABLE
+        while usart.syncbusy.read().enable().bit_is_set() {}
 
-            sercom.usart().ctrla.modify(|_, w| w.enable().set_bit());
-            // wait for sync of ENABLE
-            while sercom.usart().syncbusy.read().enable().bit_is_set() {}
-        }
+        self.config = *config;
+        self.actual_baud = setting.actual_baud;
+        // Two 11 bit characters worth of cycles, each spin takes at least one.
+        self.echo_timeout = 2 * 11 * (self.clock_hz / setting.actual_baud);
+        Some(setting.actual_baud)
+    }
 
-        Self {
-            padout,
-            sercom,
-            transmit_enable,
-        }
+    /// The baud rate the hardware is really running at, which may differ a
+    /// little from the one asked for.
+    pub fn actual_baud(&self) -> u32 {
+        self.actual_baud
+    }
+
+    pub fn free(self) -> (PADS, S) {
+        (self.padout, self.sercom)
+    }
+
+    /// Read back every transmitted word and report `UartError::Collision` when
+    /// it doesn't match. The transceiver has to keep its receiver enabled while
+    /// driving the bus for this to work.
+    pub fn set_collision_detection(&mut self, enabled: bool) {
+        self.collision_detection = enabled;
+    }
+
+    fn usart(&self) -> &USART {
+        self.sercom.usart()
+    }
+
+    fn dre(&self) -> bool {
+        self.usart().intflag.read().dre().bit_is_set()
+    }
+
+    pub fn enable_rxc_interrupt(&self) {
+        self.usart().intenset.write(|w| w.rxc().set_bit());
     }
 
+    pub fn enable_error_interrupt(&self) {
+        self.usart().intenset.write(|w| w.error().set_bit());
+    }
+}
+
+impl<P: OutputPin> FeatherUartBus<P> {
+    /// Sets up SERCOM0 on the Feather's RX/TX pins, clocked from GCLK0, at
+    /// 9600 baud. Use `configure` afterwards for other line settings.
     pub fn easy_new(
         clocks: &mut GenericClockController,
         sercom0: SERCOM0,
@@ -111,46 +266,26 @@ impl<P: OutputPin> UartBus<P> {
         tx: Pa10<Input<Floating>>,
         port: &mut Port,
         transmit_enable: P,
-    ) -> UartBus<P>
+    ) -> Self
     where
         <P as embedded_hal::digital::v2::OutputPin>::Error: core::fmt::Debug,
     {
         let gclk0 = clocks.gclk0();
         UartBus::new(
             &clocks.sercom0_core(&gclk0).unwrap(),
-            9600.hz(),
+            UartConfig::new(9600),
             sercom0,
             pm,
             (rx.into_pad(port), tx.into_pad(port)),
             transmit_enable,
         )
     }
-
-    pub fn free(self) -> (Padout, SERCOM0) {
-        (self.padout, self.sercom)
-    }
-
-    fn usart(&self) -> &USART {
-        return &self.sercom.usart();
-    }
-
-    fn dre(&self) -> bool {
-        self.usart().intflag.read().dre().bit_is_set()
-    }
-
-    pub fn enable_rxc_interrupt(&self) {
-        self.usart().intenset.write(|w| w.rxc().set_bit());
-    }
-
-    pub fn enable_error_interrupt(&self) {
-        self.usart().intenset.write(|w| w.error().set_bit());
-    }
 }
 
 type Word = u16;
 
-impl<P: OutputPin> serial::Write<Word> for UartBus<P> {
-    type Error = ();
+impl<S: Sercom, PADS: Padout<S>, P: OutputPin> serial::Write<Word> for UartBus<S, PADS, P> {
+    type Error = UartError;
 
     fn write(&mut self, word: Word) -> nb::Result<(), Self::Error> {
         unsafe {
@@ -174,9 +309,11 @@ impl<P: OutputPin> serial::Write<Word> for UartBus<P> {
     }
 }
 
-impl<P: OutputPin> serial::Read<Word> for UartBus<P> {
-    type Error = ();
+impl<S: Sercom, PADS: Padout<S>, P: OutputPin> serial::Read<Word> for UartBus<S, PADS, P> {
+    type Error = UartError;
 
+    /// A word received with an error is discarded and the error reported
+    /// instead. Reading clears the error, including the ERROR interrupt flag.
     fn read(&mut self) -> nb::Result<Word, Self::Error> {
         let has_data = self.usart().intflag.read().rxc().bit_is_set();
 
@@ -184,39 +321,104 @@ impl<P: OutputPin> serial::Read<Word> for UartBus<P> {
             return Err(nb::Error::WouldBlock);
         }
 
+        // STATUS describes the word in DATA, so check it before reading
+        let status = self.usart().status.read();
         let data = self.usart().data.read().bits();
 
-        Ok(data)
+        // An overrun means words were lost before this one, which is still good
+        let overrun = status.bufovf().bit_is_set();
+        if overrun {
+            self.overruns = self.overruns.wrapping_add(1);
+        }
+        let error = if status.ferr().bit_is_set() {
+            Some(UartError::FramingError)
+        } else if status.perr().bit_is_set() {
+            Some(UartError::ParityError)
+        } else {
+            None
+        };
+
+        if overrun || error.is_some() {
+            self.usart().status.write(|w| {
+                w.bufovf().set_bit();
+                w.ferr().set_bit();
+                w.perr().set_bit()
+            });
+            self.usart().intflag.write(|w| w.error().set_bit());
+        }
+        match error {
+            Some(error) => Err(nb::Error::Other(error)),
+            None => Ok(data),
+        }
     }
 }
 
-impl<P: OutputPin> Default<Word> for UartBus<P> {}
+impl<S: Sercom, PADS: Padout<S>, P: OutputPin> Default<Word> for UartBus<S, PADS, P> {}
+
+impl<S: Sercom, PADS: Padout<S>, P: OutputPin> UartBus<S, PADS, P> {
+    fn send_word(&mut self, word: Word) -> Result<(), UartError> {
+        nb::block!(self.write(word))?;
+        if !self.collision_detection {
+            return Ok(());
+        }
+
+        let mut spins = 0;
+        let echo = loop {
+            match <Self as serial::Read<Word>>::read(self) {
+                Ok(echo) => break echo,
+                Err(nb::Error::WouldBlock) if spins < self.echo_timeout => spins += 1,
+                _ => return Err(UartError::Collision),
+            }
+        };
+        if echo != word {
+            return Err(UartError::Collision);
+        }
+        Ok(())
+    }
+}
 
-impl<P: OutputPin> Bus for UartBus<P>
+impl<S: Sercom, PADS: Padout<S>, P: OutputPin> Bus for UartBus<S, PADS, P>
 where
     <P as embedded_hal::digital::v2::OutputPin>::Error: core::fmt::Debug,
 {
-    type Error = ();
-    // Ignore all sorts of errors for now kthx.
-    fn send(&mut self, data: &[Word]) {
+    type Error = UartError;
+    fn send(&mut self, data: &[Word]) -> Result<(), Self::Error> {
         self.transmit_enable.set_high().unwrap();
-        for byte in data.iter() {
-            let _ = nb::block!(self.write(*byte));
-        }
-        self.transmit_enable.set_low().unwrap()
+        let result = data.iter().try_for_each(|word| self.send_word(*word));
+        self.transmit_enable.set_low().unwrap();
+        result
     }
     fn read(&mut self) -> nb::Result<Word, Self::Error> {
-        <UartBus<P> as serial::Read<Word>>::read(self)
+        <Self as serial::Read<Word>>::read(self)
+    }
+    fn is_collision(error: &Self::Error) -> bool {
+        *error == UartError::Collision
+    }
+    fn line_error(error: &Self::Error) -> Option<LineError> {
+        match error {
+            UartError::FramingError => Some(LineError::Framing),
+            UartError::ParityError => Some(LineError::Parity),
+            UartError::Collision => None,
+        }
+    }
+    fn take_overruns(&mut self) -> u32 {
+        core::mem::take(&mut self.overruns)
+    }
+    fn delay_us(&mut self, us: u32) {
+        cortex_m::asm::delay(us.saturating_mul(self.cycles_per_us));
+    }
+    fn supports_baud(&self, baud: u32) -> bool {
+        UartConfig {
+            baud,
+            ..self.config
+        }
+        .reaches(self.clock_hz)
+    }
+    /// Keeps the other line settings.
+    fn set_baud(&mut self, baud: u32) {
+        self.configure(&UartConfig {
+            baud,
+            ..self.config
+        });
     }
-}
-
-const SHIFT: u64 = 32;
-
-fn calculate_baud_value(baudrate: u32, clk_freq: u32, n_samples: u8) -> u16 {
-    let sample_rate = (n_samples as u64 * baudrate as u64) << 32;
-    let ratio = sample_rate / clk_freq as u64;
-    let scale = (1u64 << SHIFT) - ratio;
-    let baud_calculated = (65536u64 * scale) >> SHIFT;
-
-    return baud_calculated as u16;
 }
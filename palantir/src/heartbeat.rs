@@ -0,0 +1,346 @@
+//! Keepalive between master and slaves.
+//!
+//! The master pings every slave with `Liveness` and reports when one goes
+//! quiet or comes back. Slaves answer with a `Watchdog`, which also runs a
+//! failsafe callback once the master has been silent for too long, so coils
+//! don't stay energized after the master crashes.
+
+use crate::common::*;
+use crate::error::Error;
+use crate::messages::{Frame, Message, PingData, PongData};
+use crate::time::{elapsed, is_due, Instant};
+use crate::{Bus, Palantir};
+
+/// How often the master pings each slave by default.
+pub const DEFAULT_PING_INTERVAL_US: u32 = 100_000;
+/// Default silence after which a slave is reported offline. Three missed pings.
+pub const DEFAULT_OFFLINE_TIMEOUT_US: u32 =
+    3 * DEFAULT_PING_INTERVAL_US + DEFAULT_PING_INTERVAL_US / 2;
+
+/// Slave side: answers pings and calls `failsafe` if the master goes silent.
+pub struct Watchdog<F: FnMut()> {
+    timeout_us: u32,
+    last_heard: Instant,
+    tripped: bool,
+    failsafe: F,
+}
+
+impl<F: FnMut()> Watchdog<F> {
+    /// `failsafe` should put the board in a safe state, e.g. de-energize all
+    /// coils. It runs once each time the watchdog trips.
+    pub fn new(timeout_us: u32, now: Instant, failsafe: F) -> Self {
+        Watchdog {
+            timeout_us,
+            last_heard: now,
+            tripped: false,
+            failsafe,
+        }
+    }
+
+    /// Counts as hearing from the master. Re-arms a tripped watchdog.
+    pub fn feed(&mut self, now: Instant) {
+        self.last_heard = now;
+        self.tripped = false;
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped
+    }
+
+    /// Runs the failsafe if the master has been silent past the timeout.
+    /// Returns whether the watchdog is tripped.
+    pub fn check(&mut self, now: Instant) -> bool {
+        if !self.tripped && elapsed(self.last_heard, now) >= self.timeout_us {
+            self.tripped = true;
+            (self.failsafe)();
+        }
+        self.tripped
+    }
+
+    /// Reads the bus, answering pings itself. Only frames the master sends
+    /// feed the watchdog, see `from_master`; frames other than pings are
+    /// handed back.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<Frame>, Error<B::Error>> {
+        let frame = match palantir.read() {
+            Ok(frame) => frame,
+            Err(e) => {
+                self.check(now);
+                return match e {
+                    nb::Error::WouldBlock => Ok(None),
+                    nb::Error::Other(e) => Err(e),
+                };
+            }
+        };
+        if from_master(&frame.message) {
+            self.feed(now);
+        } else {
+            self.check(now);
+        }
+
+        match frame.message {
+            Message::Ping(data) => {
+                let pong = Message::Pong(PongData::new(palantir.address(), data.sequence()));
+                palantir.send(MASTER_ADDRESS, &pong)?;
+                Ok(None)
+            }
+            _ => Ok(Some(frame)),
+        }
+    }
+}
+
+/// Whether only the master sends `message`. Frames don't say who sent them,
+/// so this keeps slave-to-slave traffic such as tokens from feeding the
+/// watchdog.
+fn from_master(message: &Message) -> bool {
+    matches!(
+        message,
+        Message::Ping(_)
+            | Message::Poll
+            | Message::DiscoveryRequest(_)
+            | Message::GameUpdate(_)
+            | Message::GetBaudRates
+            | Message::SwitchBaud(_)
+            | Message::ConfirmBaud
+            | Message::GetParam(_)
+            | Message::SetParam(_)
+            | Message::ListParams(_)
+    )
+}
+
+/// The failsafe is left out, closures can't be printed.
+impl<F: FnMut()> core::fmt::Debug for Watchdog<F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("Watchdog")
+            .field("timeout_us", &self.timeout_us)
+            .field("last_heard", &self.last_heard)
+            .field("tripped", &self.tripped)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LivenessEvent {
+    /// A slave answered a ping for the first time, or again after being offline.
+    Online(Address),
+    /// A slave hasn't answered pings for the offline timeout.
+    Offline(Address),
+    /// Anything but a pong.
+    Frame(Frame),
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Peer {
+    address: Address,
+    online: bool,
+    last_seen: Instant,
+    next_ping: Instant,
+}
+
+/// Master side: pings every slave and tracks which ones are alive. Slaves
+/// start out offline until they first answer.
+#[derive(Debug)]
+pub struct Liveness {
+    peers: [Option<Peer>; MAX_SLAVES],
+    interval_us: u32,
+    timeout_us: u32,
+    sequence: u16,
+}
+
+impl Liveness {
+    pub fn new(slaves: &SlaveAddresses, now: Instant) -> Self {
+        let mut peers = [None; MAX_SLAVES];
+        for (peer, address) in peers.iter_mut().zip(slaves.iter()) {
+            if *address == 0 {
+                continue;
+            }
+            *peer = Some(Peer {
+                address: *address,
+                online: false,
+                last_seen: now,
+                next_ping: now,
+            });
+        }
+        Liveness {
+            peers,
+            interval_us: DEFAULT_PING_INTERVAL_US,
+            timeout_us: DEFAULT_OFFLINE_TIMEOUT_US,
+            sequence: 0,
+        }
+    }
+
+    pub fn set_ping_interval(&mut self, interval_us: u32) {
+        self.interval_us = interval_us;
+    }
+
+    pub fn set_offline_timeout(&mut self, timeout_us: u32) {
+        self.timeout_us = timeout_us;
+    }
+
+    /// `None` if `address` isn't tracked.
+    pub fn is_online(&self, address: Address) -> Option<bool> {
+        self.peers
+            .iter()
+            .flatten()
+            .find(|peer| peer.address == address)
+            .map(|peer| peer.online)
+    }
+
+    /// Pings the next slave that is due and notes pongs. The interval is
+    /// only kept as well as this is called, at most one ping goes out per call.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<LivenessEvent>, Error<B::Error>> {
+        match palantir.read() {
+            Ok(Frame {
+                message: Message::Pong(data),
+                ..
+            }) => {
+                let peer = self
+                    .peers
+                    .iter_mut()
+                    .flatten()
+                    .find(|peer| peer.address == data.responder_address());
+                if let Some(peer) = peer {
+                    peer.last_seen = now;
+                    if !peer.online {
+                        peer.online = true;
+                        return Ok(Some(LivenessEvent::Online(peer.address)));
+                    }
+                }
+                return Ok(None);
+            }
+            Ok(frame) => return Ok(Some(LivenessEvent::Frame(frame))),
+            Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(e),
+        }
+
+        for peer in self.peers.iter_mut().flatten() {
+            if peer.online && elapsed(peer.last_seen, now) >= self.timeout_us {
+                peer.online = false;
+                return Ok(Some(LivenessEvent::Offline(peer.address)));
+            }
+        }
+
+        let interval = self.interval_us;
+        let due = self
+            .peers
+            .iter_mut()
+            .flatten()
+            .find(|peer| is_due(peer.next_ping, now));
+        if let Some(peer) = due {
+            self.sequence = self.sequence.wrapping_add(1);
+            palantir.send(peer.address, &Message::Ping(PingData::new(self.sequence)))?;
+            peer.next_ping = now.wrapping_add(interval);
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::CodecError;
+    use crate::messages::TokenData;
+    use crate::tests::MockBus;
+    use core::cell::Cell;
+
+    #[test]
+    fn watchdog_trips_once_and_rearms() {
+        let fired = Cell::new(0);
+        let mut watchdog = Watchdog::new(1_000, 0, || fired.set(fired.get() + 1));
+
+        assert!(!watchdog.check(999));
+        assert!(watchdog.check(1_000));
+        assert!(watchdog.check(5_000));
+        assert_eq!(fired.get(), 1);
+
+        watchdog.feed(6_000);
+        assert!(!watchdog.is_tripped());
+        assert!(watchdog.check(7_000));
+        assert_eq!(fired.get(), 2);
+    }
+
+    #[test]
+    fn master_tracks_slave_liveness() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        let fired = Cell::new(false);
+        let mut watchdog = Watchdog::new(10_000, 0, || fired.set(true));
+        let mut liveness = Liveness::new(master.slaves().unwrap(), 0);
+        liveness.set_ping_interval(1_000);
+        liveness.set_offline_timeout(3_000);
+        assert_eq!(liveness.is_online(2), Some(false));
+
+        let mut events = Vec::new();
+        for now in (0..5_000).step_by(100) {
+            for _ in 0..MAX_MESSAGE_LEN {
+                assert_eq!(watchdog.poll(&mut slave, now), Ok(None));
+                if let Some(event) = liveness.poll(&mut master, now).unwrap() {
+                    events.push(event);
+                }
+            }
+        }
+        assert_eq!(events, [LivenessEvent::Online(2)]);
+        assert_eq!(liveness.is_online(2), Some(true));
+        assert!(!fired.get());
+
+        // Slave stops answering
+        let mut events = Vec::new();
+        for now in (5_000..10_000).step_by(100) {
+            if let Some(event) = liveness.poll(&mut master, now).unwrap() {
+                events.push(event);
+            }
+        }
+        assert_eq!(events, [LivenessEvent::Offline(2)]);
+    }
+
+    #[test]
+    fn slave_failsafe_when_master_silent() {
+        let (_master_bus, slave_bus) = MockBus::pair();
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        let fired = Cell::new(false);
+        let mut watchdog = Watchdog::new(1_000, 0, || fired.set(true));
+
+        assert_eq!(watchdog.poll(&mut slave, 500), Ok(None));
+        assert!(!fired.get());
+        assert_eq!(watchdog.poll(&mut slave, 1_000), Ok(None));
+        assert!(fired.get());
+    }
+
+    #[test]
+    fn only_master_traffic_feeds_the_watchdog() {
+        let mut buses = MockBus::multidrop(3).into_iter();
+        let mut slave = Palantir::new_slave(2, buses.next().unwrap());
+        let mut other = Palantir::new_slave(3, buses.next().unwrap());
+        let mut other_bus = buses.next().unwrap();
+        let fired = Cell::new(false);
+        let mut watchdog = Watchdog::new(1_000, 0, || fired.set(true));
+
+        // Another slave handing over the token
+        other
+            .send(2, &Message::Token(TokenData::new(3, 1)))
+            .unwrap();
+        let frame = (0..MAX_MESSAGE_LEN).find_map(|_| watchdog.poll(&mut slave, 999).unwrap());
+        assert!(matches!(frame.map(|f| f.message), Some(Message::Token(_))));
+        assert!(!fired.get());
+
+        // Garbage still lets the failsafe run
+        other_bus.send(&[(1 << 8) | 2, 0]).unwrap();
+        let result = (0..MAX_MESSAGE_LEN)
+            .map(|_| watchdog.poll(&mut slave, 1_000))
+            .find(|result| result.is_err());
+        assert_eq!(
+            result,
+            Some(Err(Error::Codec(CodecError::InvalidLength(0))))
+        );
+        assert!(fired.get());
+    }
+}
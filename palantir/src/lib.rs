@@ -2,38 +2,142 @@
 
 mod common;
 pub use common::*;
+mod error;
+pub use error::*;
 
+pub mod asynch;
+pub mod baud;
+pub mod capture;
+pub mod device;
 #[cfg(feature = "feather_bus")]
 pub mod feather_bus;
+pub mod heartbeat;
+pub mod logging;
 pub mod messages;
+pub mod params;
 mod parser;
+pub mod queue;
+mod stats;
+pub use stats::*;
+pub mod router;
+pub mod scheduler;
+pub mod time;
+pub mod timesync;
+pub mod token;
+mod transaction;
+pub use transaction::*;
+pub mod uart;
 
+use device::DeviceInfo;
 pub use messages::*;
-use nb;
 use parser::Parser;
+use queue::{Overflow, Priority, Queue, PRIORITIES};
+use time::Instant;
+use timesync::ClockEstimate;
+use transaction::PendingTable;
 
 pub trait Bus {
     type Error;
 
-    fn send(&mut self, data: &[u16]);
+    /// Transmits `data`. Buses that can detect collisions should read back each
+    /// word as it goes out and stop as soon as the echo doesn't match.
+    fn send(&mut self, data: &[u16]) -> Result<(), Self::Error>;
     fn read(&mut self) -> nb::Result<u16, Self::Error>;
-}
 
-pub enum Error {
-    NotMaster,
-    SendToSelf,
-    InvalidDiscoveryAck,
-    /// Slave received a different message when it was anticipating a discovery request.
-    InvalidDiscoveryReq,
-    Other,
+    /// Whether `error` from `send` means another node was transmitting at the
+    /// same time, so the frame is worth retrying.
+    fn is_collision(_error: &Self::Error) -> bool {
+        false
+    }
+
+    /// Busy-waits for about `us` microseconds. Used to back off after a collision.
+    fn delay_us(&mut self, _us: u32) {}
+
+    /// What kind of line error `error` from `read` is, if any. Only used to
+    /// keep `Stats`.
+    fn line_error(_error: &Self::Error) -> Option<LineError> {
+        None
+    }
+
+    /// Receive overruns since the last call that were reported along with a
+    /// word that is still good, e.g. by a UART that lost the words before
+    /// the one it holds. Only used to keep `Stats`.
+    fn take_overruns(&mut self) -> u32 {
+        0
+    }
+
+    /// Whether the bus can run at `baud`. Buses that keep the default stay at
+    /// the rate they were set up with, see `baud`.
+    fn supports_baud(&self, _baud: u32) -> bool {
+        false
+    }
+
+    /// Switches to `baud`, which `supports_baud` accepted. Anything on its way
+    /// in or out may be lost.
+    fn set_baud(&mut self, _baud: u32) {}
+
+    /// The local time, e.g. from a free-running timer. Read right after a
+    /// sync frame finished arriving or going out, so the closer to the line
+    /// the better. Buses that return `None` can't take part in time sync, see
+    /// `timesync`.
+    fn timestamp(&self) -> Option<Instant> {
+        None
+    }
 }
 
-pub struct Palantir<B: Bus> {
+/// `Q` is how many received frames can wait to be read, see `receive_all`.
+#[derive(Debug)]
+pub struct Palantir<B: Bus, const Q: usize = DEFAULT_RX_QUEUE_LEN> {
     parser: Parser,
     address: Address,
     bus: B,
     slaves: Option<SlaveAddresses>,
     loopback: bool,
+    monitor: bool,
+    /// xorshift state for collision backoff, seeded from the address so
+    /// colliding nodes pick different delays.
+    rng: u32,
+    pending: PendingTable,
+    request_timeout_us: u32,
+    inbox: Queue<Frame, Q>,
+    /// One queue per `Priority`, most urgent first.
+    outbox: [Queue<Outgoing, TX_QUEUE_LEN>; 3],
+    stats: Stats,
+    /// What `GetDeviceInfo` is answered with, if anything.
+    device_info: Option<DeviceInfo>,
+    /// Negotiated protocol version per peer address. Unused slots are 0.
+    peer_versions: [(Address, u8); MAX_PEERS],
+    /// How the local clock relates to the master's. Unused on the master.
+    clock: ClockEstimate,
+}
+
+/// Every node a node can talk to: the master and all the slaves.
+const MAX_PEERS: usize = MAX_SLAVES + 1;
+
+/// A frame waiting in the transmit queue.
+#[derive(Debug)]
+struct Outgoing {
+    address: Address,
+    message: Message,
+}
+
+fn new_outbox() -> [Queue<Outgoing, TX_QUEUE_LEN>; 3] {
+    core::array::from_fn(|_| Queue::new(Overflow::DropNewest))
+}
+
+fn backoff_seed(address: Address) -> u32 {
+    0x9E37_79B9 ^ ((address as u32) << 16 | address as u32)
+}
+
+/// Returns the slave's protocol version.
+fn check_discovery_ack<E>(address: Address, msg: Message) -> Result<u8, Error<E>> {
+    match msg {
+        Message::DiscoveryAcknowledge(data) if data.responder_address() == address => {
+            Ok(data.protocol_version())
+        }
+        Message::DiscoveryAcknowledge(_) => Err(Error::NotDiscovered(address)),
+        _ => Err(Error::InvalidDiscoveryAck),
+    }
 }
 
 impl<B: Bus> Palantir<B> {
@@ -44,6 +148,16 @@ impl<B: Bus> Palantir<B> {
             bus,
             slaves: None,
             loopback: false,
+            monitor: false,
+            rng: backoff_seed(device_address),
+            pending: PendingTable::new(),
+            request_timeout_us: DEFAULT_REQUEST_TIMEOUT_US,
+            inbox: Queue::new(Overflow::DropOldest),
+            outbox: new_outbox(),
+            stats: Stats::default(),
+            device_info: None,
+            peer_versions: [(0, 0); MAX_PEERS],
+            clock: ClockEstimate::default(),
         }
     }
 
@@ -54,145 +168,801 @@ impl<B: Bus> Palantir<B> {
             bus,
             slaves: Some(slaves),
             loopback: false,
+            monitor: false,
+            rng: backoff_seed(MASTER_ADDRESS),
+            pending: PendingTable::new(),
+            request_timeout_us: DEFAULT_REQUEST_TIMEOUT_US,
+            inbox: Queue::new(Overflow::DropOldest),
+            outbox: new_outbox(),
+            stats: Stats::default(),
+            device_info: None,
+            peer_versions: [(0, 0); MAX_PEERS],
+            clock: ClockEstimate::default(),
         }
     }
 
-    fn wait_for_discovery_ack(&mut self, address: Address) -> Result<(), Error> {
-        let msg: Message = loop {
-            match self.poll() {
-                Some(msg) => break msg,
-                None => continue,
+    /// Creates a passive node that receives every frame on the bus, whatever
+    /// its address, and never transmits. Use `poll_frame` to see where each
+    /// message was headed.
+    pub fn new_monitor(bus: B) -> Self {
+        Palantir {
+            parser: Parser::new_monitor(),
+            address: 0,
+            bus,
+            slaves: None,
+            loopback: false,
+            monitor: true,
+            rng: backoff_seed(0),
+            pending: PendingTable::new(),
+            request_timeout_us: DEFAULT_REQUEST_TIMEOUT_US,
+            inbox: Queue::new(Overflow::DropOldest),
+            outbox: new_outbox(),
+            stats: Stats::default(),
+            device_info: None,
+            peer_versions: [(0, 0); MAX_PEERS],
+            clock: ClockEstimate::default(),
+        }
+    }
+
+    /// Changes how many received frames can be queued, e.g.
+    /// `Palantir::new_slave(2, bus).with_rx_queue::<32>()`.
+    pub fn with_rx_queue<const N: usize>(mut self) -> Palantir<B, N> {
+        let mut inbox = Queue::new(Overflow::DropOldest);
+        while let Some(frame) = self.inbox.pop() {
+            inbox.push(frame);
+        }
+        Palantir {
+            parser: self.parser,
+            address: self.address,
+            bus: self.bus,
+            slaves: self.slaves,
+            loopback: self.loopback,
+            monitor: self.monitor,
+            rng: self.rng,
+            pending: self.pending,
+            request_timeout_us: self.request_timeout_us,
+            inbox,
+            outbox: self.outbox,
+            stats: self.stats,
+            device_info: self.device_info,
+            peer_versions: self.peer_versions,
+            clock: self.clock,
+        }
+    }
+}
+
+impl<B: Bus, const Q: usize> Palantir<B, Q> {
+    /// Blocks until a frame arrives. Read errors are skipped, boards
+    /// powering up can put garbage on the line.
+    fn wait_for_message(&mut self) -> Message {
+        loop {
+            if let Ok(frame) = self.read() {
+                return frame.message;
             }
-        };
+        }
+    }
+
+    fn wait_for_discovery_ack(&mut self, address: Address) -> Result<(), Error<B::Error>> {
+        let msg = self.wait_for_message();
+        let version = check_discovery_ack(address, msg)?;
+        self.set_peer_version(address, version);
+        Ok(())
+    }
 
-        let _valid_data = DiscoveryAcknowledgeData::new(address);
+    pub fn address(&self) -> Address {
+        self.address
+    }
 
-        match msg {
-            Message::DiscoveryAcknowledge(_valid_data) => Ok(()),
-            _ => Err(Error::InvalidDiscoveryAck),
+    /// The slave addresses a master was created with, `None` on slaves.
+    pub fn slaves(&self) -> Option<&SlaveAddresses> {
+        self.slaves.as_ref()
+    }
+
+    fn next_random(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+
+    /// The protocol version frames to `address` are encoded with: the lower
+    /// of this node's and the one `address` announced during discovery, or
+    /// `BASE_PROTOCOL_VERSION` if it never did.
+    pub fn peer_version(&self, address: Address) -> u8 {
+        self.peer_versions
+            .iter()
+            .find(|(peer, _)| *peer == address && address != 0)
+            .map_or(BASE_PROTOCOL_VERSION, |(_, version)| *version)
+    }
+
+    /// Records the protocol version `address` speaks, e.g. for peers that
+    /// never went through discovery with this node. Ignored once `MAX_PEERS`
+    /// peers are known.
+    pub fn set_peer_version(&mut self, address: Address, version: u8) {
+        let slot = self
+            .peer_versions
+            .iter()
+            .position(|(peer, _)| *peer == address)
+            .or_else(|| self.peer_versions.iter().position(|(peer, _)| *peer == 0));
+        if let Some(index) = slot {
+            self.peer_versions[index] = (address, version.min(PROTOCOL_VERSION));
         }
     }
 
     /// This should only be called by the master device at startup!
-    pub fn discover_devices(&mut self) -> Result<(), Error> {
-        for slave in self.slaves.unwrap().iter() {
+    pub fn discover_devices(&mut self) -> Result<(), Error<B::Error>> {
+        let slaves = self.slaves.ok_or(Error::NotMaster)?;
+        for slave in slaves.iter() {
             if *slave == 0 {
                 continue;
             }
 
             let message = Message::DiscoveryRequest(DiscoveryRequestData::new(*slave));
-            match self.send(*slave, &message) {
-                Err(_) => return Err(Error::Other),
-                _ => (),
-            };
+            self.send(*slave, &message)?;
             self.wait_for_discovery_ack(*slave)?;
         }
         Ok(())
     }
 
     /// This should be called only by slave devices at startup.
-    pub fn discovery_mode(&mut self) -> Result<(), Error> {
-        let msg = loop {
-            match self.poll() {
-                Some(msg) => break msg,
-                _ => (),
-            }
-        };
+    pub fn discovery_mode(&mut self) -> Result<(), Error<B::Error>> {
+        let msg = self.wait_for_message();
+        self.acknowledge_discovery(msg)
+    }
 
+    fn acknowledge_discovery(&mut self, msg: Message) -> Result<(), Error<B::Error>> {
         match msg {
-            Message::DiscoveryRequest(_) => self.send(
-                MASTER_ADDRESS,
-                &Message::DiscoveryAcknowledge(DiscoveryAcknowledgeData::new(self.address)),
-            ),
+            Message::DiscoveryRequest(data) => {
+                self.set_peer_version(MASTER_ADDRESS, data.protocol_version());
+                let version = self.peer_version(MASTER_ADDRESS);
+                let ack =
+                    DiscoveryAcknowledgeData::new(self.address).with_protocol_version(version);
+                self.send(MASTER_ADDRESS, &Message::DiscoveryAcknowledge(ack))
+            }
             _ => Err(Error::InvalidDiscoveryReq),
         }
     }
 
-    pub fn send(&mut self, address: Address, message: &Message) -> Result<(), Error> {
+    pub fn send(&mut self, address: Address, message: &Message) -> Result<(), Error<B::Error>> {
+        self.send_frame(address, None, message)
+    }
+
+    fn send_frame(
+        &mut self,
+        address: Address,
+        transaction: Option<Transaction>,
+        message: &Message,
+    ) -> Result<(), Error<B::Error>> {
+        if self.monitor {
+            return Err(Error::MonitorMode);
+        }
+
         if !self.loopback && address == self.address {
             return Err(Error::SendToSelf);
         }
 
-        let mut data = [0u8; MAX_DATA_LEN];
-        let data_len = match messages::data_from_message(message, &mut data) {
-            Ok(len) => len,
-            _ => return Err(Error::Other),
+        // Only nodes that know the current format listen to broadcasts
+        let version = match address {
+            BROADCAST_ADDRESS => PROTOCOL_VERSION,
+            _ => self.peer_version(address),
         };
+        let features = Features::of(version);
+        let mut data = [0u8; MAX_DATA_LEN + parser::CRC_LEN];
+        let mut length = 0;
+        let mut header_len = 0;
+        if features.transactions {
+            data[0] = Transaction::to_byte(transaction);
+            length |= parser::TRANSACTION_FLAG;
+            header_len = 1;
+        }
+        let data_len =
+            header_len + messages::data_from_message(message, &mut data[header_len..MAX_DATA_LEN])?;
+        length |= data_len as u8;
+        let mut frame_len = data_len;
+        if features.crc {
+            length |= parser::CRC_FLAG;
+            let crc = parser::frame_crc(address, length, &data[..data_len]);
+            data[data_len..data_len + parser::CRC_LEN].copy_from_slice(&crc.to_le_bytes());
+            frame_len += parser::CRC_LEN;
+        }
         let mut payload = [0u16; MAX_MESSAGE_LEN];
         payload[0] = (1 << 8) | address as u16;
-        payload[1] = data_len as u16;
+        payload[1] = length as u16;
         for (place, data) in payload[2..].iter_mut().zip(data.iter()) {
             *place = *data as u16;
         }
 
         // +2 here for the address and data length bytes
-        self.bus.send(payload.split_at(2 + data_len).0);
+        let frame = payload.split_at(2 + frame_len).0;
+        for attempt in 0..MAX_SEND_ATTEMPTS {
+            match self.bus.send(frame) {
+                Ok(()) => {
+                    self.stats.frames_sent = self.stats.frames_sent.wrapping_add(1);
+                    self.stats.words_sent = self.stats.words_sent.wrapping_add(frame.len() as u32);
+                    return Ok(());
+                }
+                Err(e) if B::is_collision(&e) => {
+                    if attempt == MAX_SEND_ATTEMPTS - 1 {
+                        break;
+                    }
+                    self.stats.retries = self.stats.retries.wrapping_add(1);
+                    let window = BACKOFF_SLOT_US << attempt;
+                    let delay = self.next_random() % window;
+                    self.bus.delay_us(delay);
+                }
+                Err(e) => return Err(Error::Bus(e)),
+            }
+        }
+        Err(Error::Collision)
+    }
+
+    /// Queues `message` for `transmit`. Frames leave in order of priority, so
+    /// a coil command queued behind a long lamp update still goes out first.
+    pub fn enqueue(
+        &mut self,
+        address: Address,
+        message: Message,
+        priority: Priority,
+    ) -> Result<(), Error<B::Error>> {
+        if self.monitor {
+            return Err(Error::MonitorMode);
+        }
+        if !self.loopback && address == self.address {
+            return Err(Error::SendToSelf);
+        }
+        let queue = &mut self.outbox[priority as usize];
+        if queue.is_full() {
+            self.stats.tx_overflows = self.stats.tx_overflows.wrapping_add(1);
+            return Err(Error::TxQueueFull);
+        }
+        queue.push(Outgoing { address, message });
         Ok(())
     }
 
-    pub fn poll(&mut self) -> Option<Message> {
+    /// Frames waiting in the transmit queue for `priority`.
+    pub fn tx_queued(&self, priority: Priority) -> usize {
+        self.outbox[priority as usize].len()
+    }
+
+    /// Sends the most urgent queued frame. Returns whether there was one. A
+    /// frame lost to a collision or a bus error stays at the front of its
+    /// queue for the next call; one that can't be sent at all is dropped.
+    /// Frames are never interrupted, but a more urgent frame queued meanwhile
+    /// goes ahead of everything else.
+    pub fn transmit(&mut self) -> Result<bool, Error<B::Error>> {
+        let next = PRIORITIES.iter().find_map(|priority| {
+            let queue = *priority as usize;
+            self.outbox[queue].pop().map(|frame| (queue, frame))
+        });
+        let (queue, frame) = match next {
+            Some(next) => next,
+            None => return Ok(false),
+        };
+        match self.send(frame.address, &frame.message) {
+            Ok(()) => Ok(true),
+            Err(e @ (Error::Collision | Error::Bus(_))) => {
+                self.outbox[queue].push_front(frame);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends everything in the transmit queue.
+    pub fn transmit_all(&mut self) -> Result<(), Error<B::Error>> {
+        while self.transmit()? {}
+        Ok(())
+    }
+
+    /// How long `response` waits for an answer to a request.
+    pub fn set_request_timeout(&mut self, timeout_us: u32) {
+        self.request_timeout_us = timeout_us;
+    }
+
+    /// Sends `message` as a request. The answer is picked up by `read` and
+    /// can be collected with `response`. Peers before protocol version 2 have
+    /// no transactions, so a request to one of them times out.
+    pub fn request(
+        &mut self,
+        address: Address,
+        message: &Message,
+        now: Instant,
+    ) -> Result<PendingResponse, Error<B::Error>> {
+        let pending = self
+            .pending
+            .open(now, self.request_timeout_us)
+            .ok_or(Error::TooManyRequests)?;
+        let transaction = Some(Transaction::Request(pending.transaction));
+        if let Err(e) = self.send_frame(address, transaction, message) {
+            self.pending.close(pending.transaction);
+            return Err(e);
+        }
+        Ok(pending)
+    }
+
+    /// Answers the request with ID `transaction` from the node at `address`.
+    pub fn respond(
+        &mut self,
+        address: Address,
+        transaction: TransactionId,
+        message: &Message,
+    ) -> Result<(), Error<B::Error>> {
+        self.send_frame(address, Some(Transaction::Response(transaction)), message)
+    }
+
+    /// The response to `pending` once `read` has received it. Fails with
+    /// `Timeout` if it didn't arrive in time; after that, or after the
+    /// response has been returned, `pending` is no longer valid.
+    ///
+    /// This doesn't read the bus itself, keep calling `read` meanwhile.
+    pub fn response(
+        &mut self,
+        pending: &PendingResponse,
+        now: Instant,
+    ) -> nb::Result<Message, Error<B::Error>> {
+        let (response, expired) = self
+            .pending
+            .poll(pending.transaction, now, self.request_timeout_us)
+            .ok_or(nb::Error::Other(Error::UnknownTransaction))?;
+        match response {
+            Some(message) => {
+                self.pending.close(pending.transaction);
+                Ok(message)
+            }
+            None if expired => {
+                self.stats.timeouts = self.stats.timeouts.wrapping_add(1);
+                self.pending.close(pending.transaction);
+                Err(nb::Error::Other(Error::Timeout))
+            }
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    /// What happens to frames that arrive while the receive queue is full.
+    /// Drops the oldest by default.
+    pub fn set_rx_overflow(&mut self, policy: Overflow) {
+        self.inbox.set_policy(policy);
+    }
+
+    /// Number of received frames lost to a full receive queue.
+    pub fn rx_overflowed(&self) -> u32 {
+        self.inbox.overflowed()
+    }
+
+    /// Reads every word the bus has ready and queues the frames they complete,
+    /// for `read` to hand out later. Meant for the receive interrupt, so
+    /// bursts aren't lost while the application is busy.
+    pub fn receive_all(&mut self) -> Result<(), Error<B::Error>> {
+        loop {
+            let data = match self.read_word() {
+                Ok(data) => data,
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(e)) => return Err(e),
+            };
+            match self.receive(data) {
+                Ok(frame) => self.inbox.push(frame),
+                Err(nb::Error::WouldBlock) => (),
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns the oldest queued frame, or reads one word from the bus.
+    /// Returns `WouldBlock` until that word completes a frame, and reports bus
+    /// errors and malformed frames.
+    ///
+    /// Responses to this node's requests are kept for `response` instead of
+    /// being returned; late ones are dropped.
+    pub fn read(&mut self) -> nb::Result<Frame, Error<B::Error>> {
+        if let Some(frame) = self.inbox.pop() {
+            return Ok(frame);
+        }
+        let data = self.read_word()?;
+        self.receive(data)
+    }
+
+    /// Takes one word off the bus, keeping `Stats` on line errors and
+    /// overruns.
+    fn read_word(&mut self) -> nb::Result<u16, Error<B::Error>> {
         let data = match self.bus.read() {
-            Ok(val) => val,
-            _ => return None,
+            Ok(data) => data,
+            Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+            Err(nb::Error::Other(e)) => return Err(nb::Error::Other(self.bus_error(e))),
+        };
+        let overruns = self.bus.take_overruns();
+        if overruns > 0 {
+            // The lost words belonged to the frame being received
+            self.stats.overruns = self.stats.overruns.wrapping_add(overruns);
+            self.parser.resync();
+        }
+        Ok(data)
+    }
+
+    /// Counts line errors and drops the frame they hit before passing
+    /// `error` on.
+    fn bus_error(&mut self, error: B::Error) -> Error<B::Error> {
+        let counter = match B::line_error(&error) {
+            Some(LineError::Framing) => &mut self.stats.framing_errors,
+            Some(LineError::Parity) => &mut self.stats.parity_errors,
+            Some(LineError::Overrun) => &mut self.stats.overruns,
+            None => return Error::Bus(error),
+        };
+        *counter = counter.wrapping_add(1);
+        self.parser.resync();
+        Error::Bus(error)
+    }
+
+    /// Changes the bus rate and drops the frame being received, which can't
+    /// be finished at the new rate.
+    fn switch_baud(&mut self, baud: u32) {
+        self.bus.set_baud(baud);
+        self.parser.resync();
+    }
+
+    /// Counters for this node's traffic and errors.
+    pub fn stats(&self) -> Stats {
+        Stats {
+            resyncs: self.parser.resyncs,
+            rx_overflows: self.inbox.overflowed(),
+            ..self.stats
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.parser.resyncs = 0;
+        self.inbox.reset_overflowed();
+    }
+
+    /// Answers a `GetStats` from the master.
+    fn report_stats(&mut self, transaction: Option<Transaction>) -> Result<(), Error<B::Error>> {
+        let report = Message::StatsReport(self.stats());
+        match transaction {
+            Some(Transaction::Request(id)) => self.respond(MASTER_ADDRESS, id, &report),
+            _ => self.send(MASTER_ADDRESS, &report),
+        }
+    }
+
+    /// Answers `GetDeviceInfo` with `info` from now on. `info.address` is
+    /// filled in.
+    pub fn set_device_info(&mut self, info: DeviceInfo) {
+        self.device_info = Some(DeviceInfo {
+            address: self.address,
+            ..info
+        });
+    }
+
+    /// Answers a `GetDeviceInfo`, which only reaches here with the info set.
+    fn report_device_info(
+        &mut self,
+        info: DeviceInfo,
+        transaction: Option<Transaction>,
+    ) -> Result<(), Error<B::Error>> {
+        let report = Message::DeviceInfo(info);
+        match transaction {
+            Some(Transaction::Request(id)) => self.respond(MASTER_ADDRESS, id, &report),
+            _ => self.send(MASTER_ADDRESS, &report),
+        }
+    }
+
+    /// Parses a word that was already taken off the bus.
+    /// Slaves answer `GetStats` here without handing it out, and nodes with
+    /// device info `GetDeviceInfo`.
+    fn receive(&mut self, data: u16) -> nb::Result<Frame, Error<B::Error>> {
+        self.stats.words_received = self.stats.words_received.wrapping_add(1);
+        let frame = match self.parser.ingest(data) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => return Err(nb::Error::WouldBlock),
+            Err(e) => {
+                if e == CodecError::CrcMismatch {
+                    self.stats.crc_failures = self.stats.crc_failures.wrapping_add(1);
+                } else {
+                    self.stats.malformed = self.stats.malformed.wrapping_add(1);
+                }
+                return Err(nb::Error::Other(e.into()));
+            }
         };
-        self.parser.ingest(data);
-        self.parser.poll_message()
+        self.stats.frames_received = self.stats.frames_received.wrapping_add(1);
+        // Monitors only watch
+        if self.monitor {
+            return Ok(frame);
+        }
+        // Nobody answers broadcasts, they'd all talk at once
+        if frame.address == BROADCAST_ADDRESS {
+            return match frame.message {
+                Message::Sync(_) | Message::SyncFollowUp(_) => {
+                    self.receive_sync(&frame.message);
+                    Err(nb::Error::WouldBlock)
+                }
+                _ => Ok(frame),
+            };
+        }
+        match (frame.transaction, &frame.message) {
+            (Some(Transaction::Response(id)), _) => {
+                self.pending.complete(id, frame.message);
+                Err(nb::Error::WouldBlock)
+            }
+            (transaction, Message::GetStats) if self.address != MASTER_ADDRESS => {
+                self.report_stats(transaction)?;
+                Err(nb::Error::WouldBlock)
+            }
+            (transaction, Message::GetDeviceInfo) => match self.device_info {
+                Some(info) => {
+                    self.report_device_info(info, transaction)?;
+                    Err(nb::Error::WouldBlock)
+                }
+                None => Ok(frame),
+            },
+            _ => Ok(frame),
+        }
+    }
+
+    /// The master's clock, see `timesync`. `None` if the bus can't tell the
+    /// time or, on slaves, until the first sync arrived.
+    pub fn bus_time(&self) -> Option<Instant> {
+        self.to_bus_time(self.bus.timestamp()?)
+    }
+
+    /// Converts `local`, taken from the same clock as `Bus::timestamp`, to
+    /// the master's clock. E.g. for the time a switch event was seen.
+    pub fn to_bus_time(&self, local: Instant) -> Option<Instant> {
+        match self.slaves {
+            Some(_) => Some(local),
+            None => self.clock.bus_time(local),
+        }
+    }
+
+    /// Takes a sample for `bus_time` from the master's sync frames.
+    fn receive_sync(&mut self, message: &Message) {
+        if self.slaves.is_some() {
+            return;
+        }
+        match message {
+            Message::Sync(data) => {
+                if let Some(now) = self.bus.timestamp() {
+                    self.clock.sync(data.sequence(), now);
+                }
+            }
+            Message::SyncFollowUp(data) => self.clock.follow_up(data.sequence(), data.timestamp()),
+            _ => (),
+        }
+    }
+
+    /// Like `read`, but discards errors.
+    pub fn poll(&mut self) -> Option<Message> {
+        self.poll_frame().map(|frame| frame.message)
+    }
+
+    /// Like `poll`, but also reports the address the message was sent to.
+    /// Mostly useful on monitor-mode nodes.
+    pub fn poll_frame(&mut self) -> Option<Frame> {
+        self.read().ok()
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use super::*;
+    use messages::test::arb_message;
+    use proptest::prelude::*;
+    use queue::Priority;
+    use std::cell::RefCell;
     use std::collections::VecDeque;
+    use std::rc::Rc;
 
     impl<B: Bus> Palantir<B> {
-        fn new_loopback(address: Address, bus: B) -> Self {
+        pub(crate) fn new_loopback(address: Address, bus: B) -> Self {
             Palantir {
                 parser: Parser::new(address),
                 address,
                 bus,
                 slaves: None,
                 loopback: true,
+                monitor: false,
+                rng: backoff_seed(address),
+                pending: PendingTable::new(),
+                request_timeout_us: DEFAULT_REQUEST_TIMEOUT_US,
+                inbox: queue::Queue::new(Overflow::DropOldest),
+                outbox: new_outbox(),
+                stats: Stats::default(),
+                device_info: None,
+                peer_versions: [(0, 0); MAX_PEERS],
+                clock: ClockEstimate::default(),
+            }
+        }
+
+        /// A master that went through discovery with every slave in
+        /// `slaves`, so frames to them use the current format.
+        pub(crate) fn discovered_master(slaves: SlaveAddresses, bus: B) -> Self {
+            let mut master = Palantir::new_master(slaves, bus);
+            for slave in slaves.iter().filter(|address| **address != 0) {
+                master.set_peer_version(*slave, PROTOCOL_VERSION);
             }
+            master
+        }
+
+        /// A slave the master has discovered.
+        pub(crate) fn discovered_slave(address: Address, bus: B) -> Self {
+            let mut slave = Palantir::new_slave(address, bus);
+            slave.set_peer_version(MASTER_ADDRESS, PROTOCOL_VERSION);
+            slave
         }
     }
 
-    struct MockBus {
-        buf: VecDeque<u16>,
+    #[derive(PartialEq, Debug)]
+    pub(crate) enum MockError {
+        Collision,
+        Framing,
+    }
+
+    /// Reading this word gives `MockError::Framing` instead.
+    pub(crate) const FRAMING_ERROR: u16 = 0xFFFF;
+    /// Words with this bit set read as the rest of the word, after an overrun.
+    pub(crate) const OVERRUN: u16 = 0x4000;
+
+    /// Each word comes with the baud rate it was sent at.
+    type Queue = Rc<RefCell<VecDeque<(u16, u32)>>>;
+
+    pub(crate) struct MockBus {
+        /// Words waiting to be read by this node.
+        pub(crate) buf: Queue,
+        /// Where sent words end up. Just `buf` unless this bus has peers.
+        peers: Vec<Queue>,
+        /// Number of upcoming sends that fail with a collision.
+        pub(crate) collisions: u32,
+        pub(crate) delays: Vec<u32>,
+        /// Words sent at another rate read as framing errors.
+        pub(crate) baud: u32,
+        /// Highest rate `supports_baud` accepts.
+        pub(crate) max_baud: u32,
+        /// What `timestamp` returns.
+        pub(crate) time: Option<Instant>,
+        overruns: u32,
     }
 
     impl MockBus {
-        fn new() -> Self {
+        /// A bus that reads back everything sent on it.
+        pub(crate) fn new() -> Self {
+            let buf = Queue::default();
+            Self::with_queues(buf.clone(), vec![buf])
+        }
+
+        /// Two ends of a point to point link. Each reads what the other sends.
+        pub(crate) fn pair() -> (Self, Self) {
+            let mut nodes = Self::multidrop(2);
+            let b = nodes.pop().unwrap();
+            (nodes.pop().unwrap(), b)
+        }
+
+        /// `count` nodes on one bus. Each reads what any of the others send.
+        pub(crate) fn multidrop(count: usize) -> Vec<Self> {
+            let queues: Vec<Queue> = (0..count).map(|_| Queue::default()).collect();
+            (0..count)
+                .map(|i| {
+                    let peers = (0..count)
+                        .filter(|j| *j != i)
+                        .map(|j| queues[j].clone())
+                        .collect();
+                    Self::with_queues(queues[i].clone(), peers)
+                })
+                .collect()
+        }
+
+        /// Words waiting to be read, without their rates.
+        pub(crate) fn words(&self) -> Vec<u16> {
+            self.buf.borrow().iter().map(|(word, _)| *word).collect()
+        }
+
+        fn with_queues(buf: Queue, peers: Vec<Queue>) -> Self {
             Self {
-                buf: VecDeque::new(),
+                buf,
+                peers,
+                collisions: 0,
+                delays: Vec::new(),
+                baud: baud::BAUD_RATES[0],
+                max_baud: baud::BAUD_RATES[0],
+                time: None,
+                overruns: 0,
             }
         }
     }
 
     impl Bus for MockBus {
-        type Error = ();
-        fn send(&mut self, data: &[u16]) {
-            for x in data {
-                self.buf.push_back(*x);
+        type Error = MockError;
+        fn send(&mut self, data: &[u16]) -> Result<(), Self::Error> {
+            if self.collisions > 0 {
+                self.collisions -= 1;
+                return Err(MockError::Collision);
+            }
+            for peer in self.peers.iter() {
+                peer.borrow_mut()
+                    .extend(data.iter().map(|word| (*word, self.baud)));
             }
+            Ok(())
         }
 
         fn read(&mut self) -> nb::Result<u16, Self::Error> {
-            match self.buf.pop_front() {
-                Some(val) => Ok(val),
-                None => Err(nb::Error::Other(())),
+            match self.buf.borrow_mut().pop_front() {
+                Some((FRAMING_ERROR, _)) => Err(nb::Error::Other(MockError::Framing)),
+                Some((_, baud)) if baud != self.baud => Err(nb::Error::Other(MockError::Framing)),
+                Some((val, _)) if val & OVERRUN != 0 => {
+                    self.overruns += 1;
+                    Ok(val & !OVERRUN)
+                }
+                Some((val, _)) => Ok(val),
+                None => Err(nb::Error::WouldBlock),
             }
         }
+
+        fn is_collision(error: &Self::Error) -> bool {
+            *error == MockError::Collision
+        }
+
+        fn delay_us(&mut self, us: u32) {
+            self.delays.push(us);
+        }
+
+        fn line_error(error: &Self::Error) -> Option<LineError> {
+            match error {
+                MockError::Framing => Some(LineError::Framing),
+                MockError::Collision => None,
+            }
+        }
+
+        fn take_overruns(&mut self) -> u32 {
+            core::mem::take(&mut self.overruns)
+        }
+
+        fn supports_baud(&self, baud: u32) -> bool {
+            baud <= self.max_baud
+        }
+
+        fn set_baud(&mut self, baud: u32) {
+            self.baud = baud;
+        }
+
+        fn timestamp(&self) -> Option<Instant> {
+            self.time
+        }
+    }
+
+    /// Polls until a whole frame has been read or the bus runs dry.
+    pub(crate) fn next_frame<B: Bus, const Q: usize>(
+        palantir: &mut Palantir<B, Q>,
+    ) -> Option<Frame> {
+        for _ in 0..MAX_MESSAGE_LEN {
+            if let Some(frame) = palantir.poll_frame() {
+                return Some(frame);
+            }
+        }
+        None
     }
 
-    fn get_mocked_slave(address: Address) -> Palantir<MockBus> {
-        Palantir::new_loopback(address, MockBus::new())
+    proptest! {
+        #[test]
+        fn prop_send_poll_round_trip(address in any::<Address>(), msg in arb_message()) {
+            // A monitor reads it back, so nothing gets answered or consumed
+            let (bus, monitor_bus) = MockBus::pair();
+            let mut palantir = Palantir::new_loopback(address, bus);
+            let mut monitor = Palantir::new_monitor(monitor_bus);
+            prop_assert!(palantir.send(address, &msg).is_ok());
+
+            let mut frame: Option<Frame> = None;
+            for _ in 0..MAX_MESSAGE_LEN {
+                frame = monitor.poll_frame();
+                if frame.is_some() {
+                    break;
+                }
+            }
+            prop_assert_eq!(frame, Some(Frame { address, transaction: None, message: msg }));
+            prop_assert!(monitor.bus.buf.borrow().is_empty());
+        }
     }
 
     #[test]
     fn echo_bus() {
         let mut bus = MockBus::new();
-        bus.send(&[5]);
+        bus.send(&[5]).unwrap();
         match bus.read() {
             Ok(v) => assert_eq!(v, 5),
             Err(_) => panic!("did not get same value back"),
@@ -201,12 +971,12 @@ mod tests {
 
     #[test]
     fn discovery_req_transmit() {
-        let mut bus = MockBus::new();
+        let bus = MockBus::new();
         let mut palantir = Palantir::new_loopback(MASTER_ADDRESS, bus);
 
         let msg = Message::DiscoveryRequest(DiscoveryRequestData::new(9));
 
-        palantir.send(MASTER_ADDRESS, &msg);
+        assert!(palantir.send(MASTER_ADDRESS, &msg).is_ok());
 
         let mut msg: Option<Message> = None;
 
@@ -221,4 +991,523 @@ mod tests {
             _ => panic!("got something that wasnt a discovery request"),
         }
     }
+
+    #[test]
+    fn monitor_sees_other_addresses() {
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        let msg = Message::DiscoveryRequest(DiscoveryRequestData::new(2));
+        assert!(master.send(2, &msg).is_ok());
+
+        let mut monitor = Palantir::new_monitor(master.bus);
+        let mut frame: Option<Frame> = None;
+
+        for _ in 0..MAX_MESSAGE_LEN {
+            frame = monitor.poll_frame();
+            if frame.is_some() {
+                break;
+            }
+        }
+        match frame {
+            Some(Frame {
+                address,
+                message: Message::DiscoveryRequest(data),
+                ..
+            }) => {
+                assert_eq!(address, 2);
+                assert_eq!(data.target_address(), 2);
+            }
+            _ => panic!("monitor did not pick up the frame"),
+        }
+    }
+
+    #[test]
+    fn slave_ignores_other_addresses() {
+        let mut master = Palantir::new_master([2, 3, 0, 0, 0, 0, 0], MockBus::new());
+        let msg = Message::DiscoveryRequest(DiscoveryRequestData::new(3));
+        assert!(master.send(3, &msg).is_ok());
+
+        let mut slave = Palantir::new_slave(2, master.bus);
+        for _ in 0..MAX_MESSAGE_LEN {
+            assert!(slave.poll().is_none());
+        }
+    }
+
+    #[test]
+    fn read_reports_malformed_frames() {
+        let mut bus = MockBus::new();
+        bus.send(&[(1 << 8) | 2, 1, 0xFF]).unwrap();
+        let mut slave = Palantir::new_slave(2, bus);
+
+        for _ in 0..2 {
+            assert_eq!(slave.read(), Err(nb::Error::WouldBlock));
+        }
+        assert_eq!(
+            slave.read(),
+            Err(nb::Error::Other(Error::Codec(
+                CodecError::UnknownMessageId(0xFF)
+            )))
+        );
+        assert_eq!(slave.read(), Err(nb::Error::WouldBlock));
+    }
+
+    #[test]
+    fn read_rejects_invalid_lengths() {
+        let mut bus = MockBus::new();
+        bus.send(&[(1 << 8) | 2, 0]).unwrap();
+        bus.send(&[(1 << 8) | 2, MAX_DATA_LEN as u16 + 1]).unwrap();
+        let mut slave = Palantir::new_slave(2, bus);
+
+        for len in [0, MAX_DATA_LEN as u8 + 1] {
+            assert_eq!(slave.read(), Err(nb::Error::WouldBlock));
+            assert_eq!(
+                slave.read(),
+                Err(nb::Error::Other(Error::Codec(CodecError::InvalidLength(
+                    len
+                ))))
+            );
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn codec_errors_are_the_source() {
+        use std::error::Error as _;
+
+        let error: Error<()> = CodecError::CrcMismatch.into();
+        let source = error.source().and_then(|e| e.downcast_ref::<CodecError>());
+        assert_eq!(source, Some(&CodecError::CrcMismatch));
+        assert!(Error::<()>::Timeout.source().is_none());
+    }
+
+    #[test]
+    fn send_retries_after_collision() {
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        master.bus.collisions = 2;
+        let msg = Message::DiscoveryRequest(DiscoveryRequestData::new(2));
+
+        assert_eq!(master.send(2, &msg), Ok(()));
+        assert_eq!(master.bus.delays.len(), 2);
+        for (attempt, delay) in master.bus.delays.iter().enumerate() {
+            assert!(*delay < BACKOFF_SLOT_US << attempt);
+        }
+        assert!(!master.bus.buf.borrow().is_empty());
+    }
+
+    #[test]
+    fn send_gives_up_after_max_attempts() {
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        master.bus.collisions = MAX_SEND_ATTEMPTS;
+        let msg = Message::DiscoveryRequest(DiscoveryRequestData::new(2));
+
+        assert_eq!(master.send(2, &msg), Err(Error::Collision));
+        assert!(master.bus.buf.borrow().is_empty());
+        // No point waiting after the last attempt
+        assert_eq!(master.bus.delays.len(), MAX_SEND_ATTEMPTS as usize - 1);
+        assert_eq!(master.stats().retries, MAX_SEND_ATTEMPTS - 1);
+    }
+
+    #[test]
+    fn backoff_differs_between_nodes() {
+        let mut a = Palantir::new_slave(2, MockBus::new());
+        let mut b = Palantir::new_slave(3, MockBus::new());
+        let a: Vec<u32> = (0..4).map(|_| a.next_random() % BACKOFF_SLOT_US).collect();
+        let b: Vec<u32> = (0..4).map(|_| b.next_random() % BACKOFF_SLOT_US).collect();
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn monitor_never_sends() {
+        let mut monitor = Palantir::new_monitor(MockBus::new());
+        let msg = Message::DiscoveryRequest(DiscoveryRequestData::new(2));
+        assert_eq!(monitor.send(2, &msg), Err(Error::MonitorMode));
+        assert!(monitor.bus.buf.borrow().is_empty());
+    }
+
+    #[test]
+    fn request_gets_matching_response() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::discovered_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::discovered_slave(2, slave_bus);
+
+        let first = master
+            .request(2, &Message::Ping(PingData::new(1)), 0)
+            .unwrap();
+        let second = master
+            .request(2, &Message::Ping(PingData::new(2)), 0)
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(master.response(&first, 0), Err(nb::Error::WouldBlock));
+
+        // Answer out of order
+        let a = next_frame(&mut slave).unwrap();
+        let b = next_frame(&mut slave).unwrap();
+        for frame in [b, a] {
+            let (transaction, sequence) = match frame {
+                Frame {
+                    transaction: Some(Transaction::Request(id)),
+                    message: Message::Ping(data),
+                    ..
+                } => (id, data.sequence()),
+                _ => panic!("expected a ping request"),
+            };
+            let pong = Message::Pong(PongData::new(2, sequence));
+            slave.respond(MASTER_ADDRESS, transaction, &pong).unwrap();
+        }
+
+        // Responses are routed, not handed out by `read`
+        assert_eq!(next_frame(&mut master), None);
+        assert_eq!(
+            master.response(&first, 10),
+            Ok(Message::Pong(PongData::new(2, 1)))
+        );
+        assert_eq!(
+            master.response(&second, 10),
+            Ok(Message::Pong(PongData::new(2, 2)))
+        );
+        assert_eq!(
+            master.response(&first, 10),
+            Err(nb::Error::Other(Error::UnknownTransaction))
+        );
+    }
+
+    #[test]
+    fn request_times_out() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::discovered_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        master.set_request_timeout(1_000);
+
+        let pending = master.request(2, &Message::Poll, 0).unwrap();
+        assert_eq!(master.response(&pending, 999), Err(nb::Error::WouldBlock));
+        assert_eq!(
+            master.response(&pending, 1_000),
+            Err(nb::Error::Other(Error::Timeout))
+        );
+
+        // A late answer is dropped
+        let mut slave = Palantir::discovered_slave(2, slave_bus);
+        slave
+            .respond(MASTER_ADDRESS, pending.transaction(), &Message::Poll)
+            .unwrap();
+        assert_eq!(next_frame(&mut master), None);
+    }
+
+    #[test]
+    fn pending_table_is_bounded() {
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        for _ in 0..MAX_PENDING {
+            assert!(master.request(2, &Message::Poll, 0).is_ok());
+        }
+        assert_eq!(
+            master.request(2, &Message::Poll, 0),
+            Err(Error::TooManyRequests)
+        );
+    }
+
+    #[test]
+    fn dropped_requests_are_reclaimed() {
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        master.set_request_timeout(1_000);
+        for _ in 0..MAX_PENDING {
+            master.request(2, &Message::Poll, 0).unwrap();
+        }
+        assert_eq!(
+            master.request(2, &Message::Poll, 999),
+            Err(Error::TooManyRequests)
+        );
+
+        // Nobody asked for the responses, their slots are reused once expired
+        for _ in 0..MAX_PENDING {
+            assert!(master.request(2, &Message::Poll, 1_000).is_ok());
+        }
+    }
+
+    #[test]
+    fn receive_all_queues_bursts() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master =
+            Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus).with_rx_queue::<2>();
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        for sequence in 0..3 {
+            slave
+                .send(MASTER_ADDRESS, &Message::Ping(PingData::new(sequence)))
+                .unwrap();
+        }
+
+        assert_eq!(master.receive_all(), Ok(()));
+        assert_eq!(master.rx_overflowed(), 1);
+        for sequence in 1..3 {
+            let ping = Message::Ping(PingData::new(sequence));
+            assert_eq!(master.read().map(|f| f.message), Ok(ping));
+        }
+        assert_eq!(master.read(), Err(nb::Error::WouldBlock));
+
+        master.set_rx_overflow(Overflow::DropNewest);
+        for sequence in 0..3 {
+            slave
+                .send(MASTER_ADDRESS, &Message::Ping(PingData::new(sequence)))
+                .unwrap();
+        }
+        assert_eq!(master.receive_all(), Ok(()));
+        assert_eq!(master.rx_overflowed(), 2);
+        assert_eq!(
+            master.read().map(|f| f.message),
+            Ok(Message::Ping(PingData::new(0)))
+        );
+    }
+
+    #[test]
+    fn transmit_in_priority_order() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        let ping = |sequence| Message::Ping(PingData::new(sequence));
+
+        master.enqueue(2, ping(0), Priority::Bulk).unwrap();
+        master.enqueue(2, ping(1), Priority::RealTime).unwrap();
+        master.enqueue(2, ping(2), Priority::Bulk).unwrap();
+        assert_eq!(master.transmit(), Ok(true));
+        // A coil command jumps the queue at the next frame boundary
+        master
+            .enqueue(2, ping(3), Priority::SafetyCritical)
+            .unwrap();
+        assert_eq!(master.tx_queued(Priority::Bulk), 2);
+        assert_eq!(master.transmit_all(), Ok(()));
+        assert_eq!(master.transmit(), Ok(false));
+
+        let order: Vec<Message> = core::iter::from_fn(|| next_frame(&mut slave))
+            .map(|frame| frame.message)
+            .collect();
+        assert_eq!(order, [ping(1), ping(3), ping(0), ping(2)]);
+    }
+
+    #[test]
+    fn failed_transmit_keeps_the_frame() {
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        let coil = Message::Ping(PingData::new(7));
+        master.enqueue(2, coil, Priority::SafetyCritical).unwrap();
+
+        master.bus.collisions = MAX_SEND_ATTEMPTS;
+        assert_eq!(master.transmit(), Err(Error::Collision));
+        assert_eq!(master.tx_queued(Priority::SafetyCritical), 1);
+        assert_eq!(master.transmit(), Ok(true));
+        assert_eq!(master.tx_queued(Priority::SafetyCritical), 0);
+    }
+
+    #[test]
+    fn tx_queue_is_bounded() {
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        for _ in 0..TX_QUEUE_LEN {
+            assert_eq!(master.enqueue(2, Message::Poll, Priority::Bulk), Ok(()));
+        }
+        assert_eq!(
+            master.enqueue(2, Message::Poll, Priority::Bulk),
+            Err(Error::TxQueueFull)
+        );
+        assert_eq!(master.enqueue(2, Message::Poll, Priority::RealTime), Ok(()));
+        assert_eq!(
+            master.enqueue(1, Message::Poll, Priority::Bulk),
+            Err(Error::SendToSelf)
+        );
+    }
+
+    #[test]
+    fn stats_count_traffic_and_errors() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::new_slave(2, slave_bus);
+
+        master.bus.collisions = 1;
+        master.send(2, &Message::Poll).unwrap();
+        let garbage = [(1 << 8) | 2, 1, 0xFF].map(|word| (word, baud::BAUD_RATES[0]));
+        master.bus.peers[0].borrow_mut().extend(garbage);
+        assert_eq!(
+            next_frame(&mut slave).map(|f| f.message),
+            Some(Message::Poll)
+        );
+        assert_eq!(next_frame(&mut slave), None);
+
+        let master_stats = master.stats();
+        assert_eq!(master_stats.frames_sent, 1);
+        assert_eq!(master_stats.words_sent, 3);
+        assert_eq!(master_stats.retries, 1);
+        let slave_stats = slave.stats();
+        assert_eq!(slave_stats.frames_received, 1);
+        assert_eq!(slave_stats.words_received, 6);
+        assert_eq!(slave_stats.malformed, 1);
+
+        slave.reset_stats();
+        assert_eq!(slave.stats(), Stats::default());
+    }
+
+    #[test]
+    fn slave_answers_get_stats() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::discovered_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::discovered_slave(2, slave_bus);
+
+        let pending = master.request(2, &Message::GetStats, 0).unwrap();
+        assert_eq!(next_frame(&mut slave), None);
+        assert_eq!(next_frame(&mut master), None);
+        match master.response(&pending, 0) {
+            Ok(Message::StatsReport(stats)) => {
+                assert_eq!(stats.frames_received, 1);
+                assert_eq!(stats.words_received, 6);
+            }
+            other => panic!("expected a stats report, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn overrun_keeps_the_word_it_came_with() {
+        let mut bus = MockBus::new();
+        let poll = [(1 << 8) | 2, 1, 3];
+        // Words lost after the length, the next address word is still good
+        bus.send(&[poll[0], poll[1], poll[0] | OVERRUN]).unwrap();
+        bus.send(&poll[1..]).unwrap();
+        let mut slave = Palantir::new_slave(2, bus);
+
+        assert_eq!(
+            next_frame(&mut slave).map(|f| f.message),
+            Some(Message::Poll)
+        );
+        let stats = slave.stats();
+        assert_eq!((stats.overruns, stats.resyncs), (1, 1));
+    }
+
+    #[test]
+    fn line_error_resyncs_to_next_frame() {
+        let mut bus = MockBus::new();
+        let poll = [(1 << 8) | 2, 1, 3];
+        bus.send(&[poll[0], poll[1], FRAMING_ERROR, 3]).unwrap();
+        bus.send(&poll).unwrap();
+        let mut slave = Palantir::new_slave(2, bus);
+
+        for _ in 0..2 {
+            assert_eq!(slave.read(), Err(nb::Error::WouldBlock));
+        }
+        assert_eq!(
+            slave.read(),
+            Err(nb::Error::Other(Error::Bus(MockError::Framing)))
+        );
+        // The rest of the broken frame is ignored
+        assert_eq!(
+            next_frame(&mut slave).map(|f| f.message),
+            Some(Message::Poll)
+        );
+        assert_eq!(next_frame(&mut slave), None);
+
+        let stats = slave.stats();
+        assert_eq!(
+            (stats.framing_errors, stats.resyncs, stats.frames_received),
+            (1, 1, 1)
+        );
+    }
+
+    #[test]
+    fn discovery_negotiates_protocol_version() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 3, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::new_slave(2, slave_bus);
+
+        let request = Message::DiscoveryRequest(DiscoveryRequestData::new(2));
+        master.send(2, &request).unwrap();
+        slave.discovery_mode().unwrap();
+        master.wait_for_discovery_ack(2).unwrap();
+        assert_eq!(master.peer_version(2), PROTOCOL_VERSION);
+        assert_eq!(master.peer_version(3), BASE_PROTOCOL_VERSION);
+        assert_eq!(slave.peer_version(MASTER_ADDRESS), PROTOCOL_VERSION);
+
+        // Frames now carry a transaction and end in a CRC, which catches a
+        // flipped bit
+        master.send(2, &Message::Poll).unwrap();
+        let poll = slave.bus.words();
+        let flags = parser::CRC_FLAG | parser::TRANSACTION_FLAG;
+        assert_eq!(poll[1], flags as u16 | 2);
+        assert_eq!(poll.len(), 6);
+        slave.bus.buf.borrow_mut()[3].0 ^= 0x10;
+        let result = core::iter::from_fn(|| Some(slave.read()))
+            .find(|result| *result != Err(nb::Error::WouldBlock));
+        assert_eq!(
+            result,
+            Some(Err(nb::Error::Other(Error::Codec(CodecError::CrcMismatch))))
+        );
+        assert_eq!(
+            (slave.stats().crc_failures, slave.stats().malformed),
+            (1, 0)
+        );
+    }
+
+    #[test]
+    fn discovery_skips_garbage() {
+        let (mut master_bus, slave_bus) = MockBus::pair();
+        let mut slave = Palantir::new_slave(2, slave_bus);
+
+        // A bad length and an unknown message before the request
+        master_bus.send(&[(1 << 8) | 2, 0]).unwrap();
+        master_bus.send(&[(1 << 8) | 2, 1, 0xFF]).unwrap();
+        master_bus.send(&[(1 << 8) | 2, 2, 0, 2]).unwrap();
+        assert_eq!(slave.discovery_mode(), Ok(()));
+
+        // Anything but a request still fails
+        master_bus.send(&[(1 << 8) | 2, 1, 3]).unwrap();
+        assert_eq!(slave.discovery_mode(), Err(Error::InvalidDiscoveryReq));
+    }
+
+    // The frames in these two are exactly what the first firmware sends and
+    // expects: address, length, message ID and target or responder address.
+
+    #[test]
+    fn old_master_gets_frames_without_crc() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut old_master = master_bus;
+        let mut slave = Palantir::new_slave(2, slave_bus);
+
+        old_master.send(&[0x102, 2, 0, 2]).unwrap();
+        slave.discovery_mode().unwrap();
+        assert_eq!(slave.peer_version(MASTER_ADDRESS), BASE_PROTOCOL_VERSION);
+        assert_eq!(old_master.words(), [0x101, 2, 1, 2]);
+    }
+
+    #[test]
+    fn old_slave_gets_frames_without_crc() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut old_slave = slave_bus;
+
+        let request = Message::DiscoveryRequest(DiscoveryRequestData::new(2));
+        master.send(2, &request).unwrap();
+        // Old slaves read the address and ignore the version after it
+        assert_eq!(old_slave.words(), [0x102, 3, 0, 2, PROTOCOL_VERSION as u16]);
+        old_slave.buf.borrow_mut().clear();
+
+        old_slave.send(&[0x101, 2, 1, 2]).unwrap();
+        master.wait_for_discovery_ack(2).unwrap();
+        assert_eq!(master.peer_version(2), BASE_PROTOCOL_VERSION);
+        master.send(2, &Message::Poll).unwrap();
+        assert_eq!(old_slave.words(), [0x102, 1, 3]);
+    }
+
+    #[test]
+    fn frames_from_before_transactions_parse() {
+        // A discovery request exactly as the first firmware sends it
+        let mut bus = MockBus::new();
+        bus.send(&[0x102, 2, 0, 2]).unwrap();
+        let mut slave = Palantir::new_slave(2, bus);
+        match next_frame(&mut slave) {
+            Some(Frame {
+                address: 2,
+                transaction: None,
+                message: Message::DiscoveryRequest(data),
+            }) => {
+                assert_eq!(data.target_address(), 2);
+                assert_eq!(data.protocol_version(), BASE_PROTOCOL_VERSION);
+            }
+            other => panic!("expected a discovery request, got {:?}", other),
+        }
+
+        // Peers that never said otherwise get frames without the header
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], MockBus::new());
+        master.send(2, &Message::Poll).unwrap();
+        assert_eq!(master.bus.words(), [0x102, 1, 3]);
+    }
 }
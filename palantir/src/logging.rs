@@ -0,0 +1,461 @@
+//! Log output from slaves, sent to the master over the bus.
+//!
+//! Records travel as `LogRecord` messages at `Priority::Bulk`, so they never
+//! hold up coil or switch traffic. With the `log` feature `BusLogger` is a
+//! `log` backend that queues them on a slave, and `forward` hands them to the
+//! master's own logger. Otherwise `LogLine` prints them, e.g. to a host.
+//!
+//! Records name their module by ID, an index into a table of module paths
+//! both ends share. Records from boards that log through defmt carry the
+//! encoded defmt frame instead of text, which only a host with the slave's
+//! ELF can decode.
+
+use core::fmt;
+
+use crate::common::*;
+use crate::error::CodecError;
+
+/// Same order as `log::Level`, most severe first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level {
+    pub fn from_byte(byte: u8) -> Result<Self, CodecError> {
+        match byte {
+            1 => Ok(Level::Error),
+            2 => Ok(Level::Warn),
+            3 => Ok(Level::Info),
+            4 => Ok(Level::Debug),
+            5 => Ok(Level::Trace),
+            _ => Err(CodecError::InvalidField),
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+}
+
+/// Longest text or defmt frame a record holds. Room for the message ID, the
+/// sender, level and module in a frame.
+pub const MAX_LOG_LEN: usize = MAX_DATA_LEN - 5;
+
+/// Set in the level byte of records carrying a defmt frame.
+const DEFMT_FLAG: u8 = 0x80;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct LogRecord {
+    address: Address,
+    level: Level,
+    module: u8,
+    defmt: bool,
+    len: u8,
+    payload: [u8; MAX_LOG_LEN],
+}
+
+impl LogRecord {
+    /// `text` is cut to `MAX_LOG_LEN` bytes, at a character boundary.
+    pub fn new(sender_address: Address, level: Level, module: u8, text: &str) -> Self {
+        let mut record = Self::empty(sender_address, level, module);
+        record.push_str(text);
+        record
+    }
+
+    /// Like `new`, with the text formatted, e.g. from `format_args!`.
+    pub fn format(sender_address: Address, level: Level, module: u8, args: fmt::Arguments) -> Self {
+        let mut record = Self::empty(sender_address, level, module);
+        // Never fails, the text is cut short instead
+        let _ = fmt::write(&mut record, args);
+        record
+    }
+
+    /// A record carrying an encoded defmt frame. `None` if it doesn't fit,
+    /// a truncated frame couldn't be decoded.
+    pub fn defmt(sender_address: Address, level: Level, module: u8, frame: &[u8]) -> Option<Self> {
+        if frame.len() > MAX_LOG_LEN {
+            return None;
+        }
+        let mut record = Self::empty(sender_address, level, module);
+        record.defmt = true;
+        record.payload[..frame.len()].copy_from_slice(frame);
+        record.len = frame.len() as u8;
+        Some(record)
+    }
+
+    fn empty(address: Address, level: Level, module: u8) -> Self {
+        LogRecord {
+            address,
+            level,
+            module,
+            defmt: false,
+            len: 0,
+            payload: [0; MAX_LOG_LEN],
+        }
+    }
+
+    fn push_str(&mut self, text: &str) {
+        let room = MAX_LOG_LEN - self.len as usize;
+        let mut end = text.len().min(room);
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        let start = self.len as usize;
+        self.payload[start..start + end].copy_from_slice(&text.as_bytes()[..end]);
+        self.len += end as u8;
+    }
+
+    pub fn sender_address(&self) -> Address {
+        self.address
+    }
+
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    pub fn module(&self) -> u8 {
+        self.module
+    }
+
+    pub fn is_defmt(&self) -> bool {
+        self.defmt
+    }
+
+    /// The text or the defmt frame.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..self.len as usize]
+    }
+
+    /// `None` for defmt records, or text that isn't valid UTF-8.
+    pub fn text(&self) -> Option<&str> {
+        match self.defmt {
+            true => None,
+            false => core::str::from_utf8(self.payload()).ok(),
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, CodecError> {
+        if data.len() < 3 {
+            return Err(CodecError::Truncated {
+                expected: 3,
+                got: data.len(),
+            });
+        }
+        let payload = &data[3..];
+        if payload.len() > MAX_LOG_LEN {
+            return Err(CodecError::BufferTooSmall);
+        }
+        let mut record = Self::empty(data[0], Level::from_byte(data[1] & !DEFMT_FLAG)?, data[2]);
+        record.defmt = data[1] & DEFMT_FLAG != 0;
+        record.payload[..payload.len()].copy_from_slice(payload);
+        record.len = payload.len() as u8;
+        Ok(record)
+    }
+
+    pub fn to_slice(&self, buf: &mut [u8]) -> Result<usize, CodecError> {
+        let len = 3 + self.len as usize;
+        if len > buf.len() {
+            return Err(CodecError::BufferTooSmall);
+        }
+        buf[0] = self.address;
+        buf[1] = self.level as u8 | if self.defmt { DEFMT_FLAG } else { 0 };
+        buf[2] = self.module;
+        buf[3..len].copy_from_slice(self.payload());
+        Ok(len)
+    }
+}
+
+impl fmt::Write for LogRecord {
+    fn write_str(&mut self, text: &str) -> fmt::Result {
+        self.push_str(text);
+        Ok(())
+    }
+}
+
+/// The ID of the module `path` is in: one more than the index of the first
+/// entry of `modules` that is `path` or one of its parents, 0 if none is.
+pub fn module_id(modules: &[&str], path: &str) -> u8 {
+    let is_in = |module: &&str| {
+        path.strip_prefix(*module)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    };
+    match modules.iter().position(is_in) {
+        Some(index) if index < u8::MAX as usize => index as u8 + 1,
+        _ => 0,
+    }
+}
+
+/// The path `module_id` gave `id`, if any.
+pub fn module_name<'a>(modules: &[&'a str], id: u8) -> Option<&'a str> {
+    (id as usize)
+        .checked_sub(1)
+        .and_then(|index| modules.get(index).copied())
+}
+
+/// Formats a record as one line, e.g. `[2] WARN coils: flipper 3 hot`.
+#[derive(Debug)]
+pub struct LogLine<'a> {
+    record: &'a LogRecord,
+    modules: &'a [&'a str],
+}
+
+impl<'a> LogLine<'a> {
+    pub fn new(record: &'a LogRecord, modules: &'a [&'a str]) -> Self {
+        LogLine { record, modules }
+    }
+}
+
+impl fmt::Display for LogLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let record = self.record;
+        write!(
+            f,
+            "[{}] {} ",
+            record.sender_address(),
+            record.level().as_str()
+        )?;
+        match module_name(self.modules, record.module()) {
+            Some(name) => write!(f, "{}: ", name)?,
+            None => write!(f, "#{}: ", record.module())?,
+        }
+        write_payload(f, record)
+    }
+}
+
+fn write_payload(f: &mut fmt::Formatter, record: &LogRecord) -> fmt::Result {
+    match (record.is_defmt(), record.text()) {
+        (false, Some(text)) => f.write_str(text),
+        (false, None) => write!(f, "<invalid UTF-8: {:02x?}>", record.payload()),
+        (true, _) => write!(f, "<defmt {:02x?}>", record.payload()),
+    }
+}
+
+#[cfg(feature = "log")]
+pub use self::backend::*;
+
+#[cfg(feature = "log")]
+mod backend {
+    use core::cell::RefCell;
+    use core::fmt;
+
+    use critical_section::Mutex;
+
+    use super::{module_id, module_name, write_payload, Level, LogRecord};
+    use crate::common::*;
+    use crate::error::Error;
+    use crate::messages::Message;
+    use crate::queue::{Overflow, Priority, Queue};
+    use crate::{Bus, Palantir};
+
+    impl From<log::Level> for Level {
+        fn from(level: log::Level) -> Self {
+            match level {
+                log::Level::Error => Level::Error,
+                log::Level::Warn => Level::Warn,
+                log::Level::Info => Level::Info,
+                log::Level::Debug => Level::Debug,
+                log::Level::Trace => Level::Trace,
+            }
+        }
+    }
+
+    impl From<Level> for log::Level {
+        fn from(level: Level) -> Self {
+            match level {
+                Level::Error => log::Level::Error,
+                Level::Warn => log::Level::Warn,
+                Level::Info => log::Level::Info,
+                Level::Debug => log::Level::Debug,
+                Level::Trace => log::Level::Trace,
+            }
+        }
+    }
+
+    /// Slave side `log` backend. Holds up to `N` records until `drain` moves
+    /// them to the transmit queue; further ones are dropped.
+    ///
+    /// Meant to live in a `static` and be installed with `log::set_logger`.
+    /// Records may be logged from interrupts, the queue is guarded by a
+    /// critical section.
+    pub struct BusLogger<const N: usize> {
+        address: Address,
+        modules: &'static [&'static str],
+        /// Created on first use, `Queue::new` can't run in a `static`.
+        records: Mutex<RefCell<Option<Queue<LogRecord, N>>>>,
+    }
+
+    impl<const N: usize> BusLogger<N> {
+        /// `address` is this slave's, `modules` the table module IDs refer
+        /// to, see `module_id`.
+        pub const fn new(address: Address, modules: &'static [&'static str]) -> Self {
+            BusLogger {
+                address,
+                modules,
+                records: Mutex::new(RefCell::new(None)),
+            }
+        }
+
+        fn with_queue<R>(&self, f: impl FnOnce(&mut Queue<LogRecord, N>) -> R) -> R {
+            critical_section::with(|cs| {
+                let mut records = self.records.borrow_ref_mut(cs);
+                f(records.get_or_insert_with(|| Queue::new(Overflow::DropNewest)))
+            })
+        }
+
+        /// Records lost because the queue was full.
+        pub fn dropped(&self) -> u32 {
+            self.with_queue(|queue| queue.overflowed())
+        }
+
+        /// Moves records to `palantir`'s transmit queue at `Priority::Bulk`
+        /// while it has room. They go out with the next `transmit`.
+        pub fn drain<B: Bus, const Q: usize>(
+            &self,
+            palantir: &mut Palantir<B, Q>,
+        ) -> Result<(), Error<B::Error>> {
+            while palantir.tx_queued(Priority::Bulk) < TX_QUEUE_LEN {
+                let record = match self.with_queue(|queue| queue.pop()) {
+                    Some(record) => record,
+                    None => break,
+                };
+                palantir.enqueue(MASTER_ADDRESS, Message::LogRecord(record), Priority::Bulk)?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Leaves out the queued records, reading them needs a critical section.
+    impl<const N: usize> fmt::Debug for BusLogger<N> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("BusLogger")
+                .field("address", &self.address)
+                .field("modules", &self.modules)
+                .finish_non_exhaustive()
+        }
+    }
+
+    impl<const N: usize> log::Log for BusLogger<N> {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            // Filtering is left to `log::set_max_level`
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            let module = module_id(self.modules, record.target());
+            let record =
+                LogRecord::format(self.address, record.level().into(), module, *record.args());
+            self.with_queue(|queue| queue.push(record));
+        }
+
+        fn flush(&self) {}
+    }
+
+    /// Master side: passes a slave's record on to the master's own logger,
+    /// with the module path as the target.
+    pub fn forward(record: &LogRecord, modules: &[&str]) {
+        struct Payload<'a>(&'a LogRecord);
+
+        impl fmt::Display for Payload<'_> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                write_payload(f, self.0)
+            }
+        }
+
+        let target = module_name(modules, record.module()).unwrap_or("palantir");
+        log::log!(
+            target: target,
+            record.level().into(),
+            "[{}] {}",
+            record.sender_address(),
+            Payload(record)
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MODULES: [&str; 2] = ["slave::coils", "slave"];
+
+    #[test]
+    fn records_and_lines() {
+        assert_eq!(module_id(&MODULES, "slave::coils::flipper"), 1);
+        assert_eq!(module_id(&MODULES, "slave::lamps"), 2);
+        assert_eq!(module_id(&MODULES, "slaves"), 0);
+        assert_eq!(module_name(&MODULES, 0), None);
+
+        let record = LogRecord::format(2, Level::Warn, 1, format_args!("flipper {} hot", 3));
+        let line = LogLine::new(&record, &MODULES).to_string();
+        assert_eq!(line, "[2] WARN slave::coils: flipper 3 hot");
+
+        // Cut at a character boundary
+        let long = "é".repeat(MAX_LOG_LEN);
+        let record = LogRecord::new(2, Level::Info, 9, &long);
+        assert_eq!(record.text().map(str::len), Some(MAX_LOG_LEN - 1));
+        assert!(LogLine::new(&record, &MODULES)
+            .to_string()
+            .starts_with("[2] INFO #9: éé"));
+
+        let record = LogRecord::defmt(3, Level::Error, 2, &[1, 0xAB]).unwrap();
+        assert_eq!(record.text(), None);
+        let line = LogLine::new(&record, &MODULES).to_string();
+        assert_eq!(line, "[3] ERROR slave: <defmt [01, ab]>");
+        assert_eq!(
+            LogRecord::defmt(3, Level::Error, 2, &[0; MAX_LOG_LEN + 1]),
+            None
+        );
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn logger_sends_to_master() {
+        use crate::messages::Message;
+        use crate::tests::{next_frame, MockBus};
+        use crate::Palantir;
+        use log::Log;
+
+        static MODULE_TABLE: [&str; 2] = MODULES;
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        let logger: BusLogger<2> = BusLogger::new(2, &MODULE_TABLE);
+
+        for switch in 0..3 {
+            logger.log(
+                &log::Record::builder()
+                    .args(format_args!("switch {} stuck", switch))
+                    .level(log::Level::Warn)
+                    .target("slave::switches")
+                    .build(),
+            );
+        }
+        assert_eq!(logger.dropped(), 1);
+        logger.drain(&mut slave).unwrap();
+        slave.transmit_all().unwrap();
+
+        for switch in 0..2 {
+            let record = match next_frame(&mut master).map(|frame| frame.message) {
+                Some(Message::LogRecord(record)) => record,
+                other => panic!("expected a log record, got {:?}", other),
+            };
+            let line = LogLine::new(&record, &MODULES).to_string();
+            assert_eq!(line, format!("[2] WARN slave: switch {} stuck", switch));
+            forward(&record, &MODULES);
+        }
+        assert_eq!(next_frame(&mut master), None);
+    }
+}
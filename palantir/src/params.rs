@@ -0,0 +1,553 @@
+//! Tunable settings on slaves, e.g. coil pulse lengths or lamp brightness.
+//!
+//! A slave declares its parameters with their type, limits and default in a
+//! `Registry`, which answers `GetParam`, `SetParam` and `ListParams` and keeps
+//! values in a `Storage` so they survive a reset. The master can push a whole
+//! cabinet's configuration with `ConfigPush`.
+
+use crate::common::*;
+use crate::error::{CodecError, Error};
+use crate::messages::{Frame, Message, ParamData, ParamErrorData, ParamInfoData};
+use crate::time::Instant;
+use crate::transaction::{Sequencer, Step};
+use crate::{Bus, Palantir, Transaction};
+
+pub type ParamId = u8;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Value {
+    Bool(bool),
+    U32(u32),
+    I32(i32),
+}
+
+impl Value {
+    pub const LEN: usize = 5;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, CodecError> {
+        if data.len() < Self::LEN {
+            return Err(CodecError::Truncated {
+                expected: Self::LEN,
+                got: data.len(),
+            });
+        }
+        let bytes = [data[1], data[2], data[3], data[4]];
+        match data[0] {
+            0 => match u32::from_le_bytes(bytes) {
+                0 => Ok(Value::Bool(false)),
+                1 => Ok(Value::Bool(true)),
+                _ => Err(CodecError::InvalidField),
+            },
+            1 => Ok(Value::U32(u32::from_le_bytes(bytes))),
+            2 => Ok(Value::I32(i32::from_le_bytes(bytes))),
+            _ => Err(CodecError::InvalidField),
+        }
+    }
+
+    pub fn to_array(&self) -> [u8; Self::LEN] {
+        let (tag, bytes) = match *self {
+            Value::Bool(value) => (0, (value as u32).to_le_bytes()),
+            Value::U32(value) => (1, value.to_le_bytes()),
+            Value::I32(value) => (2, value.to_le_bytes()),
+        };
+        [tag, bytes[0], bytes[1], bytes[2], bytes[3]]
+    }
+}
+
+/// Why a parameter couldn't be read or set.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParamError {
+    UnknownParam = 0,
+    /// The value isn't of the parameter's type.
+    WrongType = 1,
+    OutOfRange = 2,
+    /// Saving the value failed. It wasn't changed.
+    Storage = 3,
+}
+
+impl ParamError {
+    pub fn from_byte(byte: u8) -> Result<Self, CodecError> {
+        match byte {
+            0 => Ok(ParamError::UnknownParam),
+            1 => Ok(ParamError::WrongType),
+            2 => Ok(ParamError::OutOfRange),
+            3 => Ok(ParamError::Storage),
+            _ => Err(CodecError::InvalidField),
+        }
+    }
+}
+
+/// A parameter's type, given by its default, and its limits.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ParamDef {
+    pub id: ParamId,
+    pub default: Value,
+    pub min: Value,
+    pub max: Value,
+}
+
+impl ParamDef {
+    pub const LEN: usize = 1 + 3 * Value::LEN;
+
+    pub const fn bool(id: ParamId, default: bool) -> Self {
+        ParamDef {
+            id,
+            default: Value::Bool(default),
+            min: Value::Bool(false),
+            max: Value::Bool(true),
+        }
+    }
+
+    pub const fn u32(id: ParamId, default: u32, min: u32, max: u32) -> Self {
+        ParamDef {
+            id,
+            default: Value::U32(default),
+            min: Value::U32(min),
+            max: Value::U32(max),
+        }
+    }
+
+    pub const fn i32(id: ParamId, default: i32, min: i32, max: i32) -> Self {
+        ParamDef {
+            id,
+            default: Value::I32(default),
+            min: Value::I32(min),
+            max: Value::I32(max),
+        }
+    }
+
+    /// Whether `value` may be stored in this parameter.
+    pub fn check(&self, value: Value) -> Result<(), ParamError> {
+        match (value, self.min, self.max) {
+            (Value::Bool(_), Value::Bool(_), Value::Bool(_)) => Ok(()),
+            (Value::U32(v), Value::U32(min), Value::U32(max)) if v >= min && v <= max => Ok(()),
+            (Value::I32(v), Value::I32(min), Value::I32(max)) if v >= min && v <= max => Ok(()),
+            (Value::U32(_), Value::U32(_), _) | (Value::I32(_), Value::I32(_), _) => {
+                Err(ParamError::OutOfRange)
+            }
+            _ => Err(ParamError::WrongType),
+        }
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, CodecError> {
+        if data.len() < Self::LEN {
+            return Err(CodecError::Truncated {
+                expected: Self::LEN,
+                got: data.len(),
+            });
+        }
+        let value = |i: usize| Value::from_slice(&data[1 + i * Value::LEN..]);
+        Ok(ParamDef {
+            id: data[0],
+            default: value(0)?,
+            min: value(1)?,
+            max: value(2)?,
+        })
+    }
+
+    pub fn to_array(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        data[0] = self.id;
+        let values = [self.default, self.min, self.max];
+        for (place, value) in data[1..].chunks_exact_mut(Value::LEN).zip(values.iter()) {
+            place.copy_from_slice(&value.to_array());
+        }
+        data
+    }
+}
+
+/// Where a `Registry` keeps values across resets, e.g. a page of NVM.
+pub trait Storage {
+    type Error;
+
+    /// The value last stored for `id`, if any.
+    fn load(&mut self, id: ParamId) -> Option<Value>;
+    fn store(&mut self, id: ParamId, value: Value) -> Result<(), Self::Error>;
+}
+
+/// For slaves without NVM. Every parameter starts at its default.
+#[derive(Debug)]
+pub struct NoStorage;
+
+impl Storage for NoStorage {
+    type Error = ();
+
+    fn load(&mut self, _id: ParamId) -> Option<Value> {
+        None
+    }
+
+    fn store(&mut self, _id: ParamId, _value: Value) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Slave side: the parameters this node has and their current values.
+#[derive(Debug)]
+pub struct Registry<S: Storage, const N: usize> {
+    defs: [ParamDef; N],
+    values: [Value; N],
+    storage: S,
+}
+
+impl<S: Storage, const N: usize> Registry<S, N> {
+    /// `ParamInfo` carries the parameter count in a byte.
+    const COUNT_FITS: () = assert!(
+        N <= u8::MAX as usize,
+        "a Registry holds at most 255 parameters"
+    );
+
+    /// Loads the values kept in `storage`. Ones that don't fit their
+    /// definition any more, e.g. after an update changed the limits, are
+    /// replaced by the default.
+    pub fn new(defs: [ParamDef; N], mut storage: S) -> Self {
+        let () = Self::COUNT_FITS;
+        let values = core::array::from_fn(|i| {
+            let def = defs[i];
+            match storage.load(def.id) {
+                Some(value) if def.check(value).is_ok() => value,
+                _ => def.default,
+            }
+        });
+        Registry {
+            defs,
+            values,
+            storage,
+        }
+    }
+
+    fn index(&self, id: ParamId) -> Option<usize> {
+        self.defs.iter().position(|def| def.id == id)
+    }
+
+    pub fn get(&self, id: ParamId) -> Option<Value> {
+        self.index(id).map(|index| self.values[index])
+    }
+
+    pub fn get_bool(&self, id: ParamId) -> Option<bool> {
+        match self.get(id) {
+            Some(Value::Bool(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_u32(&self, id: ParamId) -> Option<u32> {
+        match self.get(id) {
+            Some(Value::U32(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    pub fn get_i32(&self, id: ParamId) -> Option<i32> {
+        match self.get(id) {
+            Some(Value::I32(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Checks `value` against the definition and stores it. Storage is only
+    /// written if the value changes, so pushing the same configuration at
+    /// every boot doesn't wear out flash.
+    pub fn set(&mut self, id: ParamId, value: Value) -> Result<(), ParamError> {
+        let index = self.index(id).ok_or(ParamError::UnknownParam)?;
+        self.defs[index].check(value)?;
+        if value == self.values[index] {
+            return Ok(());
+        }
+        self.storage
+            .store(id, value)
+            .map_err(|_| ParamError::Storage)?;
+        self.values[index] = value;
+        Ok(())
+    }
+
+    /// Puts every parameter back to its default, in storage too.
+    pub fn reset(&mut self) -> Result<(), ParamError> {
+        for index in 0..N {
+            let def = self.defs[index];
+            self.set(def.id, def.default)?;
+        }
+        Ok(())
+    }
+
+    /// Answers the parameter messages in frames read elsewhere, e.g. handed
+    /// back by `Watchdog::poll`. Other frames are returned.
+    pub fn handle<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        frame: Frame,
+    ) -> Result<Option<Frame>, Error<B::Error>> {
+        let reply = match frame.message {
+            Message::GetParam(id) => match self.get(id) {
+                Some(value) => Message::Param(ParamData::new(id, value)),
+                None => Message::ParamError(ParamErrorData::new(id, ParamError::UnknownParam)),
+            },
+            Message::SetParam(data) => match self.set(data.id(), data.value()) {
+                Ok(()) => Message::Param(data),
+                Err(error) => Message::ParamError(ParamErrorData::new(data.id(), error)),
+            },
+            Message::ListParams(index) => match self.defs.get(index as usize) {
+                Some(def) => Message::ParamInfo(ParamInfoData::new(index, N as u8, *def)),
+                None => Message::ParamError(ParamErrorData::new(index, ParamError::UnknownParam)),
+            },
+            _ => return Ok(Some(frame)),
+        };
+        reply_to(palantir, frame.transaction, &reply)?;
+        Ok(None)
+    }
+}
+
+fn reply_to<B: Bus, const Q: usize>(
+    palantir: &mut Palantir<B, Q>,
+    transaction: Option<Transaction>,
+    message: &Message,
+) -> Result<(), Error<B::Error>> {
+    match transaction {
+        Some(Transaction::Request(id)) => palantir.respond(MASTER_ADDRESS, id, message),
+        _ => palantir.send(MASTER_ADDRESS, message),
+    }
+}
+
+/// One parameter value for one slave.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Setting {
+    pub address: Address,
+    pub id: ParamId,
+    pub value: Value,
+}
+
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum PushEvent {
+    /// The slave refused the setting.
+    Rejected(Setting, ParamError),
+    /// The slave didn't answer in time.
+    NoAnswer(Setting),
+    /// Read while pushing and not part of it.
+    Frame(Frame),
+}
+
+/// Master side: sends a list of settings one by one, e.g. a whole cabinet's
+/// configuration at boot.
+#[derive(Debug)]
+pub struct ConfigPush<'a> {
+    settings: &'a [Setting],
+    next: usize,
+    requests: Sequencer,
+}
+
+impl<'a> ConfigPush<'a> {
+    pub fn new(settings: &'a [Setting]) -> Self {
+        ConfigPush {
+            settings,
+            next: 0,
+            requests: Sequencer::default(),
+        }
+    }
+
+    /// Whether every setting has been sent, accepted or not.
+    pub fn is_done(&self) -> bool {
+        self.next == self.settings.len()
+    }
+
+    /// Sends the next setting once the slave answered the last one. Only
+    /// refusals and silence are reported, accepted settings pass quietly.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<PushEvent>, Error<B::Error>> {
+        let setting = self.settings.get(self.next).copied();
+        let request = setting.map(|setting| {
+            let data = ParamData::new(setting.id, setting.value);
+            (setting.address, Message::SetParam(data))
+        });
+        let response = match self.requests.poll(palantir, request, now)? {
+            Step::Frame(frame) => return Ok(Some(PushEvent::Frame(frame))),
+            Step::Waiting => return Ok(None),
+            Step::Done(response) => response,
+        };
+        let setting = match setting {
+            Some(setting) => setting,
+            None => return Ok(None),
+        };
+        self.next += 1;
+        Ok(match response {
+            Some(Message::Param(_)) => None,
+            Some(Message::ParamError(data)) => Some(PushEvent::Rejected(setting, data.error())),
+            _ => Some(PushEvent::NoAnswer(setting)),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::{next_frame, MockBus};
+    use std::collections::HashMap;
+
+    const PULSE_MS: ParamId = 1;
+    const BRIGHTNESS: ParamId = 2;
+    const INVERTED: ParamId = 3;
+
+    const DEFS: [ParamDef; 3] = [
+        ParamDef::u32(PULSE_MS, 30, 5, 100),
+        ParamDef::i32(BRIGHTNESS, 0, -8, 8),
+        ParamDef::bool(INVERTED, false),
+    ];
+
+    #[derive(Default)]
+    struct MockStorage {
+        values: HashMap<ParamId, Value>,
+        broken: bool,
+        stores: usize,
+    }
+
+    impl Storage for &mut MockStorage {
+        type Error = ();
+
+        fn load(&mut self, id: ParamId) -> Option<Value> {
+            self.values.get(&id).copied()
+        }
+
+        fn store(&mut self, id: ParamId, value: Value) -> Result<(), Self::Error> {
+            if self.broken {
+                return Err(());
+            }
+            self.values.insert(id, value);
+            self.stores += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn registry_checks_and_persists() {
+        let mut storage = MockStorage::default();
+        storage.values.insert(PULSE_MS, Value::U32(40));
+        // Stored before the limits were tightened
+        storage.values.insert(BRIGHTNESS, Value::I32(12));
+        let mut registry = Registry::new(DEFS, &mut storage);
+
+        assert_eq!(registry.get_u32(PULSE_MS), Some(40));
+        assert_eq!(registry.get_i32(BRIGHTNESS), Some(0));
+        assert_eq!(registry.get_bool(INVERTED), Some(false));
+
+        assert_eq!(
+            registry.set(PULSE_MS, Value::U32(101)),
+            Err(ParamError::OutOfRange)
+        );
+        assert_eq!(
+            registry.set(PULSE_MS, Value::I32(50)),
+            Err(ParamError::WrongType)
+        );
+        assert_eq!(
+            registry.set(9, Value::U32(50)),
+            Err(ParamError::UnknownParam)
+        );
+        assert_eq!(registry.set(INVERTED, Value::Bool(true)), Ok(()));
+        assert_eq!(registry.get(INVERTED), Some(Value::Bool(true)));
+        assert_eq!(registry.set(INVERTED, Value::Bool(true)), Ok(()));
+        assert_eq!(storage.values.get(&INVERTED), Some(&Value::Bool(true)));
+        assert_eq!(storage.stores, 1);
+
+        storage.broken = true;
+        let mut registry = Registry::new(DEFS, &mut storage);
+        // Nothing to write
+        assert_eq!(registry.set(PULSE_MS, Value::U32(40)), Ok(()));
+        assert_eq!(
+            registry.set(PULSE_MS, Value::U32(50)),
+            Err(ParamError::Storage)
+        );
+        assert_eq!(registry.get_u32(PULSE_MS), Some(40));
+    }
+
+    #[test]
+    fn slave_answers_param_requests() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::discovered_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::discovered_slave(2, slave_bus);
+        let mut registry = Registry::new(DEFS, NoStorage);
+
+        let mut ask = |message: Message| {
+            let pending = master.request(2, &message, 0).unwrap();
+            let frame = next_frame(&mut slave).unwrap();
+            assert_eq!(registry.handle(&mut slave, frame), Ok(None));
+            assert_eq!(next_frame(&mut master), None);
+            master.response(&pending, 0).unwrap()
+        };
+
+        assert_eq!(
+            ask(Message::GetParam(PULSE_MS)),
+            Message::Param(ParamData::new(PULSE_MS, Value::U32(30)))
+        );
+        assert_eq!(
+            ask(Message::SetParam(ParamData::new(
+                BRIGHTNESS,
+                Value::I32(-9)
+            ))),
+            Message::ParamError(ParamErrorData::new(BRIGHTNESS, ParamError::OutOfRange))
+        );
+        assert_eq!(
+            ask(Message::ListParams(2)),
+            Message::ParamInfo(ParamInfoData::new(2, 3, DEFS[2]))
+        );
+        assert_eq!(
+            ask(Message::ListParams(3)),
+            Message::ParamError(ParamErrorData::new(3, ParamError::UnknownParam))
+        );
+    }
+
+    #[test]
+    fn push_reports_rejected_settings() {
+        let mut buses = MockBus::multidrop(3).into_iter();
+        let mut master = Palantir::discovered_master([2, 3, 0, 0, 0, 0, 0], buses.next().unwrap());
+        let mut slaves: Vec<_> = buses
+            .zip(2..)
+            .map(|(bus, address)| {
+                (
+                    Palantir::discovered_slave(address, bus),
+                    Registry::new(DEFS, NoStorage),
+                )
+            })
+            .collect();
+
+        let settings = [
+            Setting {
+                address: 2,
+                id: PULSE_MS,
+                value: Value::U32(60),
+            },
+            Setting {
+                address: 3,
+                id: INVERTED,
+                value: Value::U32(1),
+            },
+            Setting {
+                address: 3,
+                id: BRIGHTNESS,
+                value: Value::I32(-3),
+            },
+        ];
+        let mut push = ConfigPush::new(&settings);
+        let mut events = Vec::new();
+        while !push.is_done() {
+            if let Some(event) = push.poll(&mut master, 0).unwrap() {
+                events.push(event);
+            }
+            for (slave, registry) in slaves.iter_mut() {
+                if let Some(frame) = slave.poll_frame() {
+                    assert_eq!(registry.handle(slave, frame), Ok(None));
+                }
+            }
+        }
+        assert_eq!(
+            events,
+            [PushEvent::Rejected(settings[1], ParamError::WrongType)]
+        );
+        assert_eq!(slaves[0].1.get_u32(PULSE_MS), Some(60));
+        assert_eq!(slaves[1].1.get_i32(BRIGHTNESS), Some(-3));
+    }
+}
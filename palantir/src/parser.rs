@@ -1,8 +1,23 @@
-use core::cell::Cell;
-
 use crate::common::*;
-use crate::messages::{message_from_data, Message};
+use crate::error::CodecError;
+use crate::messages::{message_from_data, Frame};
+use crate::transaction::Transaction;
+use crc::crc16;
+
+/// Set in the length word of frames that end in a CRC.
+pub const CRC_FLAG: u8 = 0x80;
+/// Set in the length word of frames whose data starts with a transaction byte.
+pub const TRANSACTION_FLAG: u8 = 0x40;
+pub const CRC_LEN: usize = 2;
+
+/// CRC-16/X25 over the address, the length word and the data of a frame.
+pub fn frame_crc(address: Address, length: u8, data: &[u8]) -> u16 {
+    let crc = crc16::update(0, &crc16::X25_TABLE, &[address, length]);
+    crc16::update(crc, &crc16::X25_TABLE, data)
+}
 
+#[derive(Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 enum ReceiverState {
     Idle,
     Receiving,
@@ -10,9 +25,12 @@ enum ReceiverState {
     Error,
 }
 
+#[derive(Debug)]
 struct Receiver {
     state: ReceiverState,
-    buffer: [u8; MAX_DATA_LEN],
+    buffer: [u8; MAX_DATA_LEN + CRC_LEN],
+    /// The length word as received, flags included.
+    length_word: u8,
     data_length: u8,
     received: u8,
 }
@@ -21,20 +39,24 @@ impl Receiver {
     pub fn new() -> Self {
         Receiver {
             state: ReceiverState::Idle,
-            buffer: [0; MAX_DATA_LEN],
+            buffer: [0; MAX_DATA_LEN + CRC_LEN],
+            length_word: 0,
             data_length: 0,
             received: 0,
         }
     }
 
+    /// In the middle of a frame.
+    pub fn is_receiving(&self) -> bool {
+        matches!(self.state, ReceiverState::Receiving)
+    }
+
     pub fn is_complete(&self) -> bool {
-        match self.state {
-            ReceiverState::Completed => true,
-            _ => false,
-        }
+        matches!(self.state, ReceiverState::Completed)
     }
 
     fn reset(&mut self) {
+        self.length_word = 0;
         self.data_length = 0;
         self.received = 0;
     }
@@ -45,27 +67,36 @@ impl Receiver {
         self.state = ReceiverState::Receiving;
     }
 
-    pub fn add_to_buffer(&mut self, data: u8) -> Result<(), ()> {
+    /// Ignore everything until the next call to `start`.
+    pub fn stop(&mut self) {
+        self.reset();
+        self.state = ReceiverState::Idle;
+    }
+
+    /// Bytes that arrive while no frame is being received are ignored.
+    pub fn add_to_buffer(&mut self, data: u8) -> Result<(), CodecError> {
         match self.state {
             ReceiverState::Receiving => {
                 if self.data_length == 0 {
-                    if data as usize > MAX_MESSAGE_LEN {
-                        return Err(());
+                    self.length_word = data;
+                    let data = data & !(CRC_FLAG | TRANSACTION_FLAG);
+                    if data == 0 || data as usize > MAX_DATA_LEN {
+                        self.state = ReceiverState::Error;
+                        return Err(CodecError::InvalidLength(data));
                     }
                     self.data_length = data;
-                } else if self.received < self.data_length {
+                } else {
                     self.buffer[self.received as usize] = data;
                     self.received += 1;
-                } else {
-                    return Err(());
                 }
             }
             ReceiverState::Idle | ReceiverState::Completed | ReceiverState::Error => {
-                return Err(());
+                return Ok(());
             }
         };
 
-        if self.received == self.data_length {
+        let crc_len = if self.has_crc() { CRC_LEN as u8 } else { 0 };
+        if self.received == self.data_length + crc_len {
             self.state = ReceiverState::Completed;
         }
 
@@ -75,20 +106,55 @@ impl Receiver {
     pub fn data(&self) -> &[u8] {
         &self.buffer[..self.data_length as usize]
     }
+
+    fn has_crc(&self) -> bool {
+        self.length_word & CRC_FLAG != 0
+    }
+
+    fn has_transaction(&self) -> bool {
+        self.length_word & TRANSACTION_FLAG != 0
+    }
+
+    /// Whether the frame's CRC, if it has one, matches.
+    pub fn crc_matches(&self, address: Address) -> bool {
+        if !self.has_crc() {
+            return true;
+        }
+        let length = self.data_length as usize;
+        let crc = u16::from_le_bytes([self.buffer[length], self.buffer[length + 1]]);
+        crc == frame_crc(address, self.length_word, self.data())
+    }
 }
 
+#[derive(Debug)]
 pub struct Parser {
     address: Address,
-    completed: Cell<Option<Message>>,
+    /// When set every frame on the bus is accepted, regardless of its address.
+    monitor: bool,
+    /// Address byte of the frame currently being received.
+    destination: Address,
     receiver: Receiver,
+    /// Frames cut short by the next address word.
+    pub resyncs: u32,
 }
 
 impl Parser {
     pub fn new(address: Address) -> Self {
         Parser {
             address,
-            completed: Cell::new(None),
+            monitor: false,
+            destination: address,
             receiver: Receiver::new(),
+            resyncs: 0,
+        }
+    }
+
+    /// Creates a parser that decodes every frame on the bus instead of only
+    /// the ones addressed to it.
+    pub fn new_monitor() -> Self {
+        Parser {
+            monitor: true,
+            ..Parser::new(0)
         }
     }
 
@@ -100,25 +166,54 @@ impl Parser {
         None
     }
 
-    pub fn ingest(&mut self, data: u16) {
-        match self.is_address_byte(data) {
-            Some(address) if address == self.address => {
-                self.receiver.start();
-                return;
-            }
-            Some(_) => (),
-            None => return,
-        };
+    /// Drops the frame being received and waits for the next address word.
+    /// For when a word was lost or garbled on the line.
+    pub fn resync(&mut self) {
+        if self.receiver.is_receiving() {
+            self.resyncs = self.resyncs.wrapping_add(1);
+        }
+        self.receiver.stop();
+    }
 
-        if self.receiver.is_complete() {
-            match message_from_data(self.receiver.data()) {
-                Ok(msg) => self.completed.set(Some(msg)),
-                _ => (),
+    /// Feeds one word from the bus into the parser and returns the frame it
+    /// completes, if any. An error means the frame being received was
+    /// malformed and has been dropped.
+    pub fn ingest(&mut self, data: u16) -> Result<Option<Frame>, CodecError> {
+        if let Some(address) = self.is_address_byte(data) {
+            if self.receiver.is_receiving() {
+                self.resyncs = self.resyncs.wrapping_add(1);
+            }
+            if self.monitor || address == self.address || address == BROADCAST_ADDRESS {
+                self.destination = address;
+                self.receiver.start();
+            } else {
+                self.receiver.stop();
             }
+            return Ok(None);
         }
-    }
 
-    pub fn poll_message(&mut self) -> Option<Message> {
-        self.completed.replace(None)
+        self.receiver.add_to_buffer(data as u8)?;
+
+        if !self.receiver.is_complete() {
+            return Ok(None);
+        }
+        if !self.receiver.crc_matches(self.destination) {
+            self.receiver.stop();
+            return Err(CodecError::CrcMismatch);
+        }
+        // Older nodes send the message right away
+        let data = self.receiver.data();
+        let (transaction, data) = if self.receiver.has_transaction() {
+            (Transaction::from_byte(data[0]), &data[1..])
+        } else {
+            (None, data)
+        };
+        let message = message_from_data(data);
+        self.receiver.stop();
+        Ok(Some(Frame {
+            address: self.destination,
+            transaction,
+            message: message?,
+        }))
     }
 }
@@ -0,0 +1,170 @@
+//! Fixed-capacity FIFO that never blocks the producer, used for the receive
+//! queue and the per-priority transmit queues.
+
+/// What `Queue::push` does when the queue is full.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Overflow {
+    /// Make room by discarding the oldest item.
+    DropOldest,
+    /// Discard the item being pushed.
+    DropNewest,
+}
+
+/// Transmit classes, most urgent first. A queued frame is only sent once
+/// every more urgent class is empty.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Priority {
+    /// E.g. firing or releasing coils.
+    SafetyCritical = 0,
+    /// E.g. switch events and game state.
+    RealTime = 1,
+    /// E.g. lamp and display updates.
+    Bulk = 2,
+}
+
+pub const PRIORITIES: [Priority; 3] =
+    [Priority::SafetyCritical, Priority::RealTime, Priority::Bulk];
+
+#[derive(Debug)]
+pub struct Queue<T, const N: usize> {
+    items: [Option<T>; N],
+    /// Index of the oldest item.
+    head: usize,
+    len: usize,
+    policy: Overflow,
+    overflowed: u32,
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub fn new(policy: Overflow) -> Self {
+        Queue {
+            items: core::array::from_fn(|_| None),
+            head: 0,
+            len: 0,
+            policy,
+            overflowed: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn set_policy(&mut self, policy: Overflow) {
+        self.policy = policy;
+    }
+
+    /// Number of items lost because the queue was full.
+    pub fn overflowed(&self) -> u32 {
+        self.overflowed
+    }
+
+    pub fn reset_overflowed(&mut self) {
+        self.overflowed = 0;
+    }
+
+    /// Adds `item` at the back. When the queue is full the overflow policy
+    /// decides which item is lost.
+    pub fn push(&mut self, item: T) {
+        if self.is_full() {
+            self.overflowed = self.overflowed.wrapping_add(1);
+            if N == 0 || self.policy == Overflow::DropNewest {
+                return;
+            }
+            self.pop();
+        }
+        self.items[(self.head + self.len) % N] = Some(item);
+        self.len += 1;
+    }
+
+    /// Puts `item` back in front of everything else, e.g. after `pop` when
+    /// it couldn't be handled yet. Counts as an overflow if the queue is full.
+    pub fn push_front(&mut self, item: T) {
+        if self.is_full() {
+            self.overflowed = self.overflowed.wrapping_add(1);
+            return;
+        }
+        self.head = (self.head + N - 1) % N;
+        self.items[self.head] = Some(item);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let item = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        item
+    }
+
+    pub fn peek(&self) -> Option<&T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.items[self.head].as_ref()
+    }
+
+    /// Oldest first, without removing anything.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        (0..self.len).filter_map(move |i| self.items[(self.head + i) % N].as_ref())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fifo_order_across_wrap() {
+        let mut queue: Queue<u8, 3> = Queue::new(Overflow::DropNewest);
+        for round in 0..4 {
+            queue.push(round);
+            queue.push(round + 10);
+            assert_eq!(queue.pop(), Some(round));
+            assert_eq!(queue.pop(), Some(round + 10));
+        }
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+
+        (0..3).for_each(|i| queue.push(i));
+        assert!(queue.iter().copied().eq(0..3));
+
+        assert_eq!(queue.pop(), Some(0));
+        queue.push_front(9);
+        assert!(queue.iter().copied().eq([9, 1, 2]));
+    }
+
+    #[test]
+    fn overflow_policies() {
+        let mut queue: Queue<u8, 2> = Queue::new(Overflow::DropNewest);
+        (1..=3).for_each(|i| queue.push(i));
+        assert_eq!(queue.overflowed(), 1);
+        assert_eq!(queue.peek(), Some(&1));
+
+        queue.set_policy(Overflow::DropOldest);
+        queue.push(4);
+        assert_eq!(queue.overflowed(), 2);
+        assert_eq!(queue.pop(), Some(2));
+        assert_eq!(queue.pop(), Some(4));
+    }
+
+    #[test]
+    fn zero_capacity_drops_everything() {
+        let mut queue: Queue<u8, 0> = Queue::new(Overflow::DropOldest);
+        queue.push(1);
+        assert_eq!(queue.overflowed(), 1);
+        assert_eq!(queue.pop(), None);
+    }
+}
@@ -0,0 +1,144 @@
+//! Hands each received frame to the handler registered for it, instead of one
+//! big `match` on `Message` in every application.
+//!
+//! Handlers are plain functions taking a context chosen by the application,
+//! e.g. its RTFM resources or a struct on the host, so a `Router` needs no
+//! allocation and can live in a `static` or a resource.
+
+use crate::common::*;
+use crate::messages::{Frame, MessageKind};
+
+/// Most handlers a `Router` holds, not counting the default one.
+pub const MAX_ROUTES: usize = 16;
+
+pub type Handler<C> = fn(&mut C, Frame);
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Route {
+    Kind(MessageKind),
+    /// Matches on `Message::sender`.
+    Sender(Address),
+}
+
+#[derive(Debug)]
+pub struct Router<C> {
+    routes: [Option<(Route, Handler<C>)>; MAX_ROUTES],
+    default: Handler<C>,
+}
+
+impl<C> Router<C> {
+    /// `default` gets every frame no other handler matches.
+    pub fn new(default: Handler<C>) -> Self {
+        Router {
+            routes: [None; MAX_ROUTES],
+            default,
+        }
+    }
+
+    /// Handles every message of `kind`. Gives `handler` back if all
+    /// `MAX_ROUTES` are taken.
+    pub fn on(&mut self, kind: MessageKind, handler: Handler<C>) -> Result<(), Handler<C>> {
+        self.add(Route::Kind(kind), handler)
+    }
+
+    /// Handles every message from `address`. Only messages that say who sent
+    /// them can match, see `Message::sender`.
+    pub fn on_sender(&mut self, address: Address, handler: Handler<C>) -> Result<(), Handler<C>> {
+        self.add(Route::Sender(address), handler)
+    }
+
+    /// Registering the same route again replaces its handler.
+    fn add(&mut self, route: Route, handler: Handler<C>) -> Result<(), Handler<C>> {
+        let slot = self
+            .routes
+            .iter()
+            .position(|r| matches!(r, Some((existing, _)) if *existing == route))
+            .or_else(|| self.routes.iter().position(Option::is_none));
+        match slot {
+            Some(index) => {
+                self.routes[index] = Some((route, handler));
+                Ok(())
+            }
+            None => Err(handler),
+        }
+    }
+
+    /// Calls the first handler, in order of registration, whose route matches
+    /// `frame`, or the default one.
+    pub fn dispatch(&self, context: &mut C, frame: Frame) {
+        let kind = frame.message.kind();
+        let sender = frame.message.sender();
+        let handler = self
+            .routes
+            .iter()
+            .flatten()
+            .find(|(route, _)| match route {
+                Route::Kind(k) => *k == kind,
+                Route::Sender(address) => sender == Some(*address),
+            })
+            .map_or(self.default, |(_, handler)| *handler);
+        handler(context, frame)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::{Message, PingData, PongData};
+
+    #[derive(Default)]
+    struct Seen {
+        pings: u32,
+        from_three: u32,
+        other: Vec<MessageKind>,
+    }
+
+    fn router() -> Router<Seen> {
+        let mut router =
+            Router::new(|seen: &mut Seen, frame| seen.other.push(frame.message.kind()));
+        router
+            .on(MessageKind::Ping, |seen, _| seen.pings += 1)
+            .unwrap();
+        router.on_sender(3, |seen, _| seen.from_three += 1).unwrap();
+        router
+    }
+
+    fn frame(message: Message) -> Frame {
+        Frame {
+            address: 2,
+            transaction: None,
+            message,
+        }
+    }
+
+    #[test]
+    fn dispatches_by_kind_and_sender() {
+        let router = router();
+        let mut seen = Seen::default();
+
+        router.dispatch(&mut seen, frame(Message::Ping(PingData::new(1))));
+        router.dispatch(&mut seen, frame(Message::Pong(PongData::new(3, 1))));
+        router.dispatch(&mut seen, frame(Message::Pong(PongData::new(4, 1))));
+        router.dispatch(&mut seen, frame(Message::Poll));
+
+        assert_eq!(seen.pings, 1);
+        assert_eq!(seen.from_three, 1);
+        assert_eq!(seen.other, [MessageKind::Pong, MessageKind::Poll]);
+    }
+
+    #[test]
+    fn replaces_and_fills_up() {
+        let mut router = router();
+        router
+            .on(MessageKind::Ping, |seen, _| seen.pings += 10)
+            .unwrap();
+        let mut seen = Seen::default();
+        router.dispatch(&mut seen, frame(Message::Ping(PingData::new(1))));
+        assert_eq!(seen.pings, 10);
+
+        for address in 0..MAX_ROUTES as Address - 2 {
+            assert!(router.on_sender(100 + address, |_, _| ()).is_ok());
+        }
+        assert!(router.on_sender(99, |_, _| ()).is_err());
+    }
+}
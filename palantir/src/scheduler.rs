@@ -0,0 +1,365 @@
+//! Master-driven polling. Slaves only speak when the master polls them, so the
+//! half-duplex bus never has two talkers at once.
+
+use crate::common::*;
+use crate::error::Error;
+use crate::messages::{Frame, Message, PollResponseData, SwitchEvent, MAX_POLL_EVENTS};
+use crate::queue::{Overflow, Queue};
+use crate::time::{elapsed, is_due, Instant};
+use crate::{Bus, Palantir};
+
+/// How long the master waits for a slave to answer a poll unless told otherwise.
+pub const DEFAULT_RESPONSE_TIMEOUT_US: u32 = 5_000;
+
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SlaveStats {
+    pub polls: u32,
+    pub responses: u32,
+    pub missed: u32,
+    pub last_latency_us: u32,
+    pub max_latency_us: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    address: Address,
+    period_us: u32,
+    priority: u8,
+    next_due: Instant,
+    stats: SlaveStats,
+}
+
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SchedulerEvent {
+    /// A slave answered its poll.
+    Response(PollResponseData),
+    /// A slave didn't answer its poll in time.
+    Missed(Address),
+    /// Something other than the expected poll response arrived.
+    Other(Frame),
+}
+
+#[derive(Debug)]
+enum State {
+    Idle,
+    Waiting { slot: usize, sent_at: Instant },
+}
+
+#[derive(Debug)]
+pub struct Scheduler {
+    slots: [Option<Slot>; MAX_SLAVES],
+    state: State,
+    response_timeout_us: u32,
+}
+
+impl Scheduler {
+    /// Polls every non-zero address in `slaves` once per `period_us`, all at
+    /// the same priority, starting at `now`.
+    pub fn new(slaves: &SlaveAddresses, period_us: u32, now: Instant) -> Self {
+        let mut slots = [None; MAX_SLAVES];
+        for (slot, address) in slots.iter_mut().zip(slaves.iter()) {
+            if *address == 0 {
+                continue;
+            }
+            *slot = Some(Slot {
+                address: *address,
+                period_us,
+                priority: 0,
+                next_due: now,
+                stats: SlaveStats::default(),
+            });
+        }
+        Scheduler {
+            slots,
+            state: State::Idle,
+            response_timeout_us: DEFAULT_RESPONSE_TIMEOUT_US,
+        }
+    }
+
+    pub fn set_response_timeout(&mut self, timeout_us: u32) {
+        self.response_timeout_us = timeout_us;
+    }
+
+    fn slot_mut(&mut self, address: Address) -> Option<&mut Slot> {
+        self.slots
+            .iter_mut()
+            .flatten()
+            .find(|slot| slot.address == address)
+    }
+
+    /// Returns false if `address` isn't being polled.
+    pub fn set_period(&mut self, address: Address, period_us: u32) -> bool {
+        self.slot_mut(address)
+            .map(|slot| slot.period_us = period_us)
+            .is_some()
+    }
+
+    /// When several slaves are due at once the highest priority goes first.
+    /// Returns false if `address` isn't being polled.
+    pub fn set_priority(&mut self, address: Address, priority: u8) -> bool {
+        self.slot_mut(address)
+            .map(|slot| slot.priority = priority)
+            .is_some()
+    }
+
+    pub fn stats(&self, address: Address) -> Option<&SlaveStats> {
+        self.slots
+            .iter()
+            .flatten()
+            .find(|slot| slot.address == address)
+            .map(|slot| &slot.stats)
+    }
+
+    /// The due slot with the highest priority, the most overdue one on ties.
+    fn next_due(&self, now: Instant) -> Option<usize> {
+        let mut best: Option<(usize, u8, u32)> = None;
+        for (index, slot) in self.slots.iter().enumerate() {
+            let slot = match slot {
+                Some(slot) if is_due(slot.next_due, now) => slot,
+                _ => continue,
+            };
+            let overdue = elapsed(slot.next_due, now);
+            match best {
+                Some((_, priority, most_overdue))
+                    if priority > slot.priority
+                        || (priority == slot.priority && most_overdue >= overdue) => {}
+                _ => best = Some((index, slot.priority, overdue)),
+            }
+        }
+        best.map(|(index, _, _)| index)
+    }
+
+    /// Polls the most overdue slave, or waits for its `PollResponse` and
+    /// counts it missed after the response timeout.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<SchedulerEvent>, Error<B::Error>> {
+        match self.state {
+            State::Idle => {
+                if let Some(index) = self.next_due(now) {
+                    // Slots are only ever filled at construction, so this can't fail.
+                    let slot = self.slots[index].as_mut().unwrap();
+                    palantir.send(slot.address, &Message::Poll)?;
+                    slot.stats.polls = slot.stats.polls.wrapping_add(1);
+                    slot.next_due = slot.next_due.wrapping_add(slot.period_us);
+                    if is_due(slot.next_due, now) {
+                        // Fell more than a whole period behind, don't try to catch up.
+                        slot.next_due = now.wrapping_add(slot.period_us);
+                    }
+                    self.state = State::Waiting {
+                        slot: index,
+                        sent_at: now,
+                    };
+                    return Ok(None);
+                }
+                match palantir.read() {
+                    Ok(frame) => Ok(Some(SchedulerEvent::Other(frame))),
+                    Err(nb::Error::WouldBlock) => Ok(None),
+                    Err(nb::Error::Other(e)) => Err(e),
+                }
+            }
+            State::Waiting { slot, sent_at } => {
+                let slot = self.slots[slot].as_mut().unwrap();
+                match palantir.read() {
+                    Ok(Frame {
+                        message: Message::PollResponse(data),
+                        ..
+                    }) if data.responder_address() == slot.address => {
+                        let latency = elapsed(sent_at, now);
+                        slot.stats.responses = slot.stats.responses.wrapping_add(1);
+                        slot.stats.last_latency_us = latency;
+                        slot.stats.max_latency_us = slot.stats.max_latency_us.max(latency);
+                        self.state = State::Idle;
+                        Ok(Some(SchedulerEvent::Response(data)))
+                    }
+                    Ok(frame) => Ok(Some(SchedulerEvent::Other(frame))),
+                    Err(nb::Error::WouldBlock) => {
+                        if elapsed(sent_at, now) < self.response_timeout_us {
+                            return Ok(None);
+                        }
+                        slot.stats.missed = slot.stats.missed.wrapping_add(1);
+                        self.state = State::Idle;
+                        Ok(Some(SchedulerEvent::Missed(slot.address)))
+                    }
+                    Err(nb::Error::Other(e)) => Err(e),
+                }
+            }
+        }
+    }
+}
+
+/// Slave side of polling: buffers switch events until the master asks for them.
+#[derive(Debug)]
+pub struct EventQueue<const N: usize> {
+    events: Queue<SwitchEvent, N>,
+}
+
+impl<const N: usize> Default for EventQueue<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> EventQueue<N> {
+    pub fn new() -> Self {
+        EventQueue {
+            events: Queue::new(Overflow::DropNewest),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Number of events that arrived while the queue was full.
+    pub fn dropped(&self) -> u32 {
+        self.events.overflowed()
+    }
+
+    pub fn push(&mut self, event: SwitchEvent) {
+        self.events.push(event);
+    }
+
+    /// Answers a `Poll` with up to `MAX_POLL_EVENTS` of the oldest events.
+    /// Events only leave the queue once they have been sent.
+    pub fn respond<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+    ) -> Result<(), Error<B::Error>> {
+        let mut response = PollResponseData::new(palantir.address());
+        let count = self.events.len().min(MAX_POLL_EVENTS);
+        for event in self.events.iter().take(count) {
+            response.push(*event)?;
+        }
+        palantir.send(MASTER_ADDRESS, &Message::PollResponse(response))?;
+        for _ in 0..count {
+            self.events.pop();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::{next_frame, MockBus};
+
+    #[test]
+    fn polls_and_collects_events() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        let mut scheduler = Scheduler::new(master.slaves().unwrap(), 1_000, 0);
+        let mut queue: EventQueue<4> = EventQueue::new();
+        queue.push(SwitchEvent {
+            switch: 7,
+            closed: true,
+        });
+
+        assert_eq!(scheduler.poll(&mut master, 0), Ok(None));
+        assert_eq!(
+            next_frame(&mut slave).map(|f| f.message),
+            Some(Message::Poll)
+        );
+        queue.respond(&mut slave).unwrap();
+        assert!(queue.is_empty());
+
+        let event = (0..MAX_MESSAGE_LEN)
+            .filter_map(|_| scheduler.poll(&mut master, 250).unwrap())
+            .next();
+        match event {
+            Some(SchedulerEvent::Response(data)) => {
+                assert_eq!(data.responder_address(), 2);
+                assert_eq!(
+                    data.events(),
+                    &[SwitchEvent {
+                        switch: 7,
+                        closed: true
+                    }]
+                );
+            }
+            _ => panic!("no poll response"),
+        }
+        let stats = scheduler.stats(2).unwrap();
+        assert_eq!((stats.polls, stats.responses), (1, 1));
+        assert_eq!(stats.last_latency_us, 250);
+
+        // Not due again until a period after the first poll
+        assert_eq!(scheduler.poll(&mut master, 999), Ok(None));
+        assert!(next_frame(&mut slave).is_none());
+        assert_eq!(scheduler.poll(&mut master, 1_000), Ok(None));
+        assert_eq!(
+            next_frame(&mut slave).map(|f| f.message),
+            Some(Message::Poll)
+        );
+    }
+
+    #[test]
+    fn reports_missed_responses() {
+        let (master_bus, _slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 0, 0, 0, 0, 0, 0], master_bus);
+        let mut scheduler = Scheduler::new(master.slaves().unwrap(), 1_000, 0);
+        scheduler.set_response_timeout(500);
+
+        assert_eq!(scheduler.poll(&mut master, 0), Ok(None));
+        assert_eq!(scheduler.poll(&mut master, 499), Ok(None));
+        assert_eq!(
+            scheduler.poll(&mut master, 500),
+            Ok(Some(SchedulerEvent::Missed(2)))
+        );
+        assert_eq!(scheduler.stats(2).unwrap().missed, 1);
+    }
+
+    #[test]
+    fn higher_priority_goes_first() {
+        let (master_bus, slave_bus) = MockBus::pair();
+        let mut master = Palantir::new_master([2, 3, 0, 0, 0, 0, 0], master_bus);
+        let mut monitor = Palantir::new_monitor(slave_bus);
+        let mut scheduler = Scheduler::new(master.slaves().unwrap(), 1_000, 0);
+        assert!(scheduler.set_priority(3, 1));
+        assert!(!scheduler.set_priority(4, 1));
+
+        assert_eq!(scheduler.poll(&mut master, 0), Ok(None));
+        assert_eq!(next_frame(&mut monitor).map(|f| f.address), Some(3));
+    }
+
+    #[test]
+    fn queue_keeps_events_beyond_one_response() {
+        let (mut master_bus, slave_bus) = MockBus::pair();
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        let mut queue: EventQueue<32> = EventQueue::new();
+        for switch in 0..20 {
+            queue.push(SwitchEvent {
+                switch,
+                closed: false,
+            });
+        }
+
+        queue.respond(&mut slave).unwrap();
+        assert_eq!(queue.len(), 20 - MAX_POLL_EVENTS);
+        master_bus.buf.borrow_mut().clear();
+        queue.respond(&mut slave).unwrap();
+        assert!(queue.is_empty());
+        assert!(master_bus.read().is_ok());
+    }
+
+    #[test]
+    fn zero_capacity_queue_answers_empty() {
+        let (mut master_bus, slave_bus) = MockBus::pair();
+        let mut slave = Palantir::new_slave(2, slave_bus);
+        let mut queue: EventQueue<0> = EventQueue::new();
+        queue.push(SwitchEvent::default());
+        assert_eq!(queue.dropped(), 1);
+
+        queue.respond(&mut slave).unwrap();
+        assert!(master_bus.read().is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::error::CodecError;
+
+const FIELDS: usize = 14;
+
+/// Counters describing bus health since the node started or `reset_stats`
+/// was called. They wrap instead of saturating.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Stats {
+    pub frames_sent: u32,
+    pub frames_received: u32,
+    /// 9-bit words put on the bus, address words included.
+    pub words_sent: u32,
+    /// 9-bit words read off the bus, including ones for other nodes.
+    pub words_received: u32,
+    /// Frames the parser dropped because they didn't decode.
+    pub malformed: u32,
+    /// Frames dropped because their CRC didn't match. Not counted in
+    /// `malformed`.
+    pub crc_failures: u32,
+    pub framing_errors: u32,
+    pub parity_errors: u32,
+    pub overruns: u32,
+    /// Frames abandoned because the next address word came before they ended.
+    pub resyncs: u32,
+    /// Received frames lost to a full receive queue.
+    pub rx_overflows: u32,
+    /// Frames refused because their transmit queue was full.
+    pub tx_overflows: u32,
+    /// Sends repeated after a collision.
+    pub retries: u32,
+    /// Requests that got no response in time.
+    pub timeouts: u32,
+}
+
+impl Stats {
+    pub const LEN: usize = FIELDS * 4;
+
+    fn fields(&self) -> [u32; FIELDS] {
+        [
+            self.frames_sent,
+            self.frames_received,
+            self.words_sent,
+            self.words_received,
+            self.malformed,
+            self.crc_failures,
+            self.framing_errors,
+            self.parity_errors,
+            self.overruns,
+            self.resyncs,
+            self.rx_overflows,
+            self.tx_overflows,
+            self.retries,
+            self.timeouts,
+        ]
+    }
+
+    pub fn from_slice(data: &[u8]) -> Result<Self, CodecError> {
+        if data.len() < Self::LEN {
+            return Err(CodecError::Truncated {
+                expected: Self::LEN,
+                got: data.len(),
+            });
+        }
+        let field = |i: usize| {
+            u32::from_le_bytes([
+                data[4 * i],
+                data[4 * i + 1],
+                data[4 * i + 2],
+                data[4 * i + 3],
+            ])
+        };
+        Ok(Stats {
+            frames_sent: field(0),
+            frames_received: field(1),
+            words_sent: field(2),
+            words_received: field(3),
+            malformed: field(4),
+            crc_failures: field(5),
+            framing_errors: field(6),
+            parity_errors: field(7),
+            overruns: field(8),
+            resyncs: field(9),
+            rx_overflows: field(10),
+            tx_overflows: field(11),
+            retries: field(12),
+            timeouts: field(13),
+        })
+    }
+
+    pub fn to_array(&self) -> [u8; Self::LEN] {
+        let mut data = [0u8; Self::LEN];
+        for (bytes, field) in data.chunks_exact_mut(4).zip(self.fields().iter()) {
+            bytes.copy_from_slice(&field.to_le_bytes());
+        }
+        data
+    }
+}
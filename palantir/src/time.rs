@@ -0,0 +1,24 @@
+/// A point in time in microseconds. The origin is whatever the `Clock` uses and
+/// the value wraps after roughly 71 minutes, so instants should only be compared
+/// through `elapsed`.
+pub type Instant = u32;
+
+pub trait Clock {
+    fn now(&self) -> Instant;
+}
+
+impl<C: Clock> Clock for &C {
+    fn now(&self) -> Instant {
+        (*self).now()
+    }
+}
+
+/// Microseconds between `since` and `now`, correct across a single wrap.
+pub fn elapsed(since: Instant, now: Instant) -> u32 {
+    now.wrapping_sub(since)
+}
+
+/// `now` is at or after `due`, assuming they are less than half the clock range apart.
+pub(crate) fn is_due(due: Instant, now: Instant) -> bool {
+    elapsed(due, now) < u32::MAX / 2
+}
@@ -0,0 +1,176 @@
+//! A time base shared by every board, so switch events from different slaves
+//! can be put in order.
+//!
+//! The master broadcasts a `Sync` every so often, followed right away by a
+//! `SyncFollowUp` with the time the `Sync` finished going out. Each slave
+//! notes when the `Sync` finished arriving, and from these pairs estimates how
+//! far off and how fast or slow its clock is. Both ends read their clock
+//! through `Bus::timestamp`; the result is `Palantir::bus_time`.
+
+use crate::common::*;
+use crate::error::Error;
+use crate::messages::{Message, SyncData};
+use crate::time::{elapsed, is_due, Instant};
+use crate::{Bus, Palantir};
+
+/// How often `SyncMaster` broadcasts by default.
+pub const DEFAULT_SYNC_INTERVAL_US: u32 = 1_000_000;
+/// Largest clock rate error the estimate will settle on, in parts per
+/// billion. Keeps one bad sample from throwing it far off.
+pub const MAX_DRIFT_PPB: i32 = 1_000_000;
+/// Samples further apart than this don't update the drift, the estimate
+/// from before the gap is kept.
+const MAX_SAMPLE_GAP_US: u32 = 60_000_000;
+
+/// Master side: broadcasts a sync every interval.
+#[derive(Debug)]
+pub struct SyncMaster {
+    interval_us: u32,
+    next_due: Instant,
+    sequence: u16,
+}
+
+impl SyncMaster {
+    /// The first sync goes out on the first `poll`.
+    pub fn new(interval_us: u32, now: Instant) -> Self {
+        SyncMaster {
+            interval_us,
+            next_due: now,
+            sequence: 0,
+        }
+    }
+
+    /// Broadcasts a sync if one is due. Returns whether it did. Fails with
+    /// `NoTimestamp` on buses that can't tell the time.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<bool, Error<B::Error>> {
+        if palantir.slaves().is_none() {
+            return Err(Error::NotMaster);
+        }
+        if !is_due(self.next_due, now) {
+            return Ok(false);
+        }
+        let started = palantir.bus.timestamp().ok_or(Error::NoTimestamp)?;
+        self.next_due = now.wrapping_add(self.interval_us);
+        let sync = SyncData::new(self.sequence, started);
+        palantir.send(BROADCAST_ADDRESS, &Message::Sync(sync))?;
+        let done = palantir.bus.timestamp().ok_or(Error::NoTimestamp)?;
+        let follow_up = SyncData::new(self.sequence, done);
+        palantir.send(BROADCAST_ADDRESS, &Message::SyncFollowUp(follow_up))?;
+        self.sequence = self.sequence.wrapping_add(1);
+        Ok(true)
+    }
+}
+
+/// Slave side: maps the local clock to the master's. Kept by `Palantir`,
+/// which feeds it the sync frames.
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct ClockEstimate {
+    /// Sequence and local arrival time of the last `Sync`.
+    sync: Option<(u16, Instant)>,
+    /// Local and master time of the last sample.
+    anchor: Option<(Instant, Instant)>,
+    /// How much faster the master's clock runs than the local one.
+    drift_ppb: i32,
+}
+
+impl ClockEstimate {
+    pub(crate) fn sync(&mut self, sequence: u16, local: Instant) {
+        self.sync = Some((sequence, local));
+    }
+
+    /// Takes a sample if `sequence` matches the last `Sync`.
+    pub(crate) fn follow_up(&mut self, sequence: u16, master: Instant) {
+        let local = match self.sync.take() {
+            Some((expected, local)) if expected == sequence => local,
+            _ => return,
+        };
+        if let Some((last, _)) = self.anchor {
+            let gap = elapsed(last, local);
+            if gap > 0 && gap <= MAX_SAMPLE_GAP_US {
+                // What's left after the current estimate, averaged with it
+                let predicted = self.bus_time(local).unwrap_or(master);
+                let error = master.wrapping_sub(predicted) as i32;
+                let correction = error as i64 * 1_000_000_000 / gap as i64 / 2;
+                let drift = (self.drift_ppb as i64 + correction)
+                    .clamp(-MAX_DRIFT_PPB as i64, MAX_DRIFT_PPB as i64);
+                self.drift_ppb = drift as i32;
+            }
+        }
+        self.anchor = Some((local, master));
+    }
+
+    /// `None` until the first sample.
+    pub(crate) fn bus_time(&self, local: Instant) -> Option<Instant> {
+        let (last_local, last_master) = self.anchor?;
+        // Negative for instants before the last sample
+        let since = local.wrapping_sub(last_local) as i32 as i64;
+        let adjust = since * self.drift_ppb as i64 / 1_000_000_000;
+        Some(last_master.wrapping_add((since + adjust) as u32))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::MockBus;
+
+    /// A clock `offset` µs ahead of true time and `ppm` parts per million fast.
+    fn skewed(now: u64, offset: u32, ppm: i64) -> Instant {
+        let skew = now as i64 * ppm / 1_000_000;
+        offset.wrapping_add((now as i64 + skew) as u32)
+    }
+
+    #[test]
+    fn slaves_follow_the_master_clock() {
+        let mut buses = MockBus::multidrop(3).into_iter();
+        let mut master = Palantir::new_master([2, 3, 0, 0, 0, 0, 0], buses.next().unwrap());
+        let mut slaves = [
+            Palantir::new_slave(2, buses.next().unwrap()),
+            Palantir::new_slave(3, buses.next().unwrap()),
+        ];
+        // One fast and far ahead, one slow
+        let clocks = [(4_000_000_000, 120), (12_345, -80)];
+
+        let mut sync = SyncMaster::new(DEFAULT_SYNC_INTERVAL_US, 0);
+        assert_eq!(sync.poll(&mut master, 0), Err(Error::NoTimestamp));
+        assert_eq!(slaves[0].bus_time(), None);
+
+        let mut worst = 0;
+        for now in (0..30_000_000u64).step_by(500) {
+            master.bus.time = Some(now as Instant);
+            sync.poll(&mut master, now as Instant).unwrap();
+            for (slave, (offset, ppm)) in slaves.iter_mut().zip(clocks.iter()) {
+                slave.bus.time = Some(skewed(now, *offset, *ppm));
+                slave.receive_all().unwrap();
+                assert_eq!(slave.poll_frame(), None);
+                // Settled after a few syncs
+                if now > 5_000_000 {
+                    let error = slave.bus_time().unwrap().wrapping_sub(now as u32) as i32;
+                    worst = worst.max(error.abs());
+                }
+            }
+        }
+        assert_eq!(master.bus_time(), Some(29_999_500));
+        assert!(worst < 100, "off by {} µs", worst);
+    }
+
+    #[test]
+    fn follow_up_needs_its_sync() {
+        let mut estimate = ClockEstimate::default();
+        estimate.follow_up(0, 1_000);
+        assert_eq!(estimate.bus_time(0), None);
+
+        estimate.sync(1, 500);
+        estimate.follow_up(2, 1_000);
+        assert_eq!(estimate.bus_time(500), None);
+
+        estimate.sync(3, 500);
+        estimate.follow_up(3, 1_000);
+        assert_eq!(estimate.bus_time(600), Some(1_100));
+        assert_eq!(estimate.bus_time(400), Some(900));
+    }
+}
@@ -0,0 +1,377 @@
+//! Token-passing media access for peer-to-peer traffic.
+//!
+//! A `Token` frame travels around the ring of node addresses in ascending order
+//! and only the node holding it may transmit. Every pass is acknowledged with a
+//! `TokenAck`. A successor that doesn't acknowledge is assumed to have left the
+//! bus and is skipped; skipped nodes are retried every `REJOIN_PASSES` passes.
+//! If the token itself gets lost, the node that has gone longest without it,
+//! staggered by its position in the ring, regenerates it.
+//!
+//! Every node needs the same ring, typically the master's slave table plus
+//! `MASTER_ADDRESS`.
+
+use crate::common::*;
+use crate::error::Error;
+use crate::messages::{Frame, Message, TokenData};
+use crate::time::{elapsed, Instant};
+use crate::{Bus, Palantir};
+
+pub const MAX_RING: usize = MAX_SLAVES + 1;
+/// How long a node may keep the token before passing it on.
+pub const DEFAULT_HOLD_US: u32 = 2_000;
+/// How long the passing node waits for a `TokenAck`.
+pub const DEFAULT_ACK_TIMEOUT_US: u32 = 2_000;
+/// How long a node waits without seeing the token before regenerating it.
+pub const DEFAULT_LOST_TIMEOUT_US: u32 = 50_000;
+/// Nodes that left are tried again after this many passes.
+pub const REJOIN_PASSES: u32 = 16;
+/// Added to the sequence number of a regenerated token so any old copy that
+/// is still around loses against it.
+const REGENERATE_STEP: u16 = 0x100;
+
+#[derive(PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TokenEvent {
+    /// This node now holds the token.
+    Acquired,
+    /// The token was handed to this node.
+    Passed(Address),
+    /// This node didn't acknowledge the token and is skipped from now on.
+    Left(Address),
+    /// The token was lost and this node made a new one.
+    Regenerated,
+    /// Any other frame addressed to this node.
+    Frame(Frame),
+}
+
+#[derive(Debug)]
+enum State {
+    Holding { since: Instant },
+    Passing { to: usize, sent_at: Instant },
+    Waiting { since: Instant },
+}
+
+/// Whether sequence `a` is the same as or newer than `b`.
+fn is_current(a: u16, b: u16) -> bool {
+    (a.wrapping_sub(b) as i16) >= 0
+}
+
+#[derive(Debug)]
+pub struct TokenRing {
+    ring: [Address; MAX_RING],
+    len: usize,
+    /// Index of this node in `ring`.
+    rank: usize,
+    state: State,
+    sequence: u16,
+    /// Bit per ring index of nodes that stopped acknowledging.
+    absent: u16,
+    /// Passes since absent nodes were last retried.
+    passes: u32,
+    hold_us: u32,
+    ack_timeout_us: u32,
+    lost_timeout_us: u32,
+}
+
+impl TokenRing {
+    /// `ring` lists the addresses taking part, zeros are ignored and this node
+    /// is added if missing. The lowest address starts out holding the token.
+    /// `None` if `address` is 0 or the ring is full without it.
+    pub fn new(address: Address, ring: &[Address], now: Instant) -> Option<Self> {
+        let mut sorted = [0; MAX_RING];
+        let mut len = 0;
+        for candidate in ring.iter().chain(core::iter::once(&address)) {
+            if *candidate == 0 || sorted[..len].contains(candidate) || len == MAX_RING {
+                continue;
+            }
+            sorted[len] = *candidate;
+            len += 1;
+        }
+        sorted[..len].sort_unstable();
+        let rank = sorted[..len].iter().position(|a| *a == address)?;
+
+        Some(TokenRing {
+            ring: sorted,
+            len,
+            rank,
+            state: if rank == 0 {
+                State::Holding { since: now }
+            } else {
+                State::Waiting { since: now }
+            },
+            sequence: 0,
+            absent: 0,
+            passes: 0,
+            hold_us: DEFAULT_HOLD_US,
+            ack_timeout_us: DEFAULT_ACK_TIMEOUT_US,
+            lost_timeout_us: DEFAULT_LOST_TIMEOUT_US,
+        })
+    }
+
+    pub fn set_hold_time(&mut self, hold_us: u32) {
+        self.hold_us = hold_us;
+    }
+
+    pub fn set_ack_timeout(&mut self, timeout_us: u32) {
+        self.ack_timeout_us = timeout_us;
+    }
+
+    pub fn set_lost_timeout(&mut self, timeout_us: u32) {
+        self.lost_timeout_us = timeout_us;
+    }
+
+    pub fn holds_token(&self) -> bool {
+        matches!(self.state, State::Holding { .. })
+    }
+
+    /// Sends `message` if this node holds the token.
+    pub fn send<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        address: Address,
+        message: &Message,
+    ) -> Result<(), Error<B::Error>> {
+        if !self.holds_token() {
+            return Err(Error::NotTokenHolder);
+        }
+        palantir.send(address, message)
+    }
+
+    /// Hands the token on before the hold time is up.
+    pub fn pass<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<(), Error<B::Error>> {
+        if !self.holds_token() {
+            return Err(Error::NotTokenHolder);
+        }
+        self.pass_after(self.rank, palantir, now)
+    }
+
+    /// Sends the token to the first present node after ring index `index`.
+    fn pass_after<B: Bus, const Q: usize>(
+        &mut self,
+        index: usize,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<(), Error<B::Error>> {
+        self.passes += 1;
+        if self.passes >= REJOIN_PASSES {
+            self.passes = 0;
+            self.absent = 0;
+        }
+
+        if self.len <= 1 {
+            self.state = State::Holding { since: now };
+            return Ok(());
+        }
+        let mut to = (index + 1) % self.len;
+        while to != self.rank && self.absent & (1 << to) != 0 {
+            to = (to + 1) % self.len;
+        }
+        if to == self.rank {
+            // Nobody else is left, keep the token.
+            self.state = State::Holding { since: now };
+            return Ok(());
+        }
+
+        let sequence = self.sequence.wrapping_add(1);
+        let token = Message::Token(TokenData::new(palantir.address(), sequence));
+        palantir.send(self.ring[to], &token)?;
+        self.sequence = sequence;
+        self.state = State::Passing { to, sent_at: now };
+        Ok(())
+    }
+
+    fn handle_frame<B: Bus, const Q: usize>(
+        &mut self,
+        frame: Frame,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<TokenEvent>, Error<B::Error>> {
+        match frame.message {
+            Message::Token(data) => {
+                let ack = Message::TokenAck(TokenData::new(palantir.address(), data.sequence()));
+                palantir.send(data.sender_address(), &ack)?;
+                if let Some(index) = self.ring[..self.len]
+                    .iter()
+                    .position(|a| *a == data.sender_address())
+                {
+                    self.absent &= !(1 << index);
+                }
+                if !is_current(data.sequence(), self.sequence) || self.holds_token() {
+                    return Ok(None);
+                }
+                self.sequence = data.sequence();
+                self.state = State::Holding { since: now };
+                Ok(Some(TokenEvent::Acquired))
+            }
+            Message::TokenAck(data) => match self.state {
+                State::Passing { to, .. }
+                    if data.sender_address() == self.ring[to]
+                        && data.sequence() == self.sequence =>
+                {
+                    self.state = State::Waiting { since: now };
+                    Ok(Some(TokenEvent::Passed(self.ring[to])))
+                }
+                _ => Ok(None),
+            },
+            _ => Ok(Some(TokenEvent::Frame(frame))),
+        }
+    }
+
+    /// Handles the next frame, then passes the token on once the hold time is
+    /// up, skips a successor that didn't take it, or regenerates a lost one.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        now: Instant,
+    ) -> Result<Option<TokenEvent>, Error<B::Error>> {
+        match palantir.read() {
+            Ok(frame) => return self.handle_frame(frame, palantir, now),
+            Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(e),
+        }
+
+        match self.state {
+            State::Holding { since } if elapsed(since, now) >= self.hold_us => {
+                self.pass_after(self.rank, palantir, now)?;
+                Ok(None)
+            }
+            State::Passing { to, sent_at } if elapsed(sent_at, now) >= self.ack_timeout_us => {
+                self.absent |= 1 << to;
+                self.pass_after(to, palantir, now)?;
+                Ok(Some(TokenEvent::Left(self.ring[to])))
+            }
+            State::Waiting { since } => {
+                let timeout = self.lost_timeout_us + self.rank as u32 * self.ack_timeout_us;
+                if elapsed(since, now) < timeout {
+                    return Ok(None);
+                }
+                self.sequence = self.sequence.wrapping_add(REGENERATE_STEP);
+                self.state = State::Holding { since: now };
+                Ok(Some(TokenEvent::Regenerated))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::tests::MockBus;
+
+    const RING: [Address; 3] = [1, 2, 3];
+
+    fn nodes(count: usize) -> Vec<(Palantir<MockBus>, TokenRing)> {
+        MockBus::multidrop(count)
+            .into_iter()
+            .zip(RING.iter())
+            .map(|(bus, address)| {
+                let palantir = Palantir::new_slave(*address, bus);
+                let mut ring = TokenRing::new(*address, &RING, 0).unwrap();
+                ring.set_hold_time(100);
+                ring.set_ack_timeout(100);
+                ring.set_lost_timeout(1_000);
+                (palantir, ring)
+            })
+            .collect()
+    }
+
+    /// Runs every node until `now`, a word at a time, and returns the events.
+    fn run(
+        nodes: &mut [(Palantir<MockBus>, TokenRing)],
+        now: Instant,
+    ) -> Vec<(Address, TokenEvent)> {
+        let mut events = Vec::new();
+        for _ in 0..MAX_MESSAGE_LEN {
+            for (palantir, ring) in nodes.iter_mut() {
+                if let Some(event) = ring.poll(palantir, now).unwrap() {
+                    events.push((palantir.address(), event));
+                }
+            }
+        }
+        events
+    }
+
+    fn holders(nodes: &[(Palantir<MockBus>, TokenRing)]) -> Vec<Address> {
+        nodes
+            .iter()
+            .filter(|(_, ring)| ring.holds_token())
+            .map(|(palantir, _)| palantir.address())
+            .collect()
+    }
+
+    #[test]
+    fn token_circulates() {
+        let mut nodes = nodes(3);
+        assert_eq!(holders(&nodes), [1]);
+
+        let events = run(&mut nodes, 100);
+        assert!(events.contains(&(1, TokenEvent::Passed(2))));
+        assert!(events.contains(&(2, TokenEvent::Acquired)));
+        assert_eq!(holders(&nodes), [2]);
+
+        run(&mut nodes, 200);
+        assert_eq!(holders(&nodes), [3]);
+        run(&mut nodes, 300);
+        assert_eq!(holders(&nodes), [1]);
+    }
+
+    #[test]
+    fn only_holder_may_send() {
+        let mut nodes = nodes(3);
+        let msg = Message::Poll;
+        let (palantir, ring) = &mut nodes[1];
+        assert_eq!(ring.send(palantir, 3, &msg), Err(Error::NotTokenHolder));
+        let (palantir, ring) = &mut nodes[0];
+        assert_eq!(ring.send(palantir, 3, &msg), Ok(()));
+    }
+
+    #[test]
+    fn skips_node_that_left() {
+        let mut nodes = nodes(3);
+        // Node 2 is gone, its words pile up unread
+        nodes.remove(1);
+
+        run(&mut nodes, 100);
+        let events = run(&mut nodes, 200);
+        assert!(events.contains(&(1, TokenEvent::Left(2))));
+        run(&mut nodes, 200);
+        assert_eq!(holders(&nodes), [3]);
+    }
+
+    #[test]
+    fn regenerates_lost_token() {
+        let mut nodes = nodes(3);
+        // Holder dies with the token
+        nodes.remove(0);
+
+        // Node 2 is first in line, node 3 waits one ack timeout longer
+        let events = run(&mut nodes, 1_099);
+        assert!(events.is_empty());
+        let events = run(&mut nodes, 1_100);
+        assert_eq!(events, [(2, TokenEvent::Regenerated)]);
+        assert_eq!(holders(&nodes), [2]);
+    }
+
+    #[test]
+    fn alone_on_the_ring() {
+        let bus = MockBus::new();
+        let mut palantir = Palantir::new_slave(5, bus);
+        let mut ring = TokenRing::new(5, &[], 0).unwrap();
+        assert!(ring.holds_token());
+        assert_eq!(ring.pass(&mut palantir, 10), Ok(()));
+        assert!(ring.holds_token());
+    }
+
+    #[test]
+    fn nodes_outside_the_ring_get_no_token() {
+        let full: [Address; MAX_RING] = core::array::from_fn(|i| i as Address + 1);
+        assert!(TokenRing::new(0, &[1, 2], 0).is_none());
+        assert!(TokenRing::new(MAX_RING as Address + 1, &full, 0).is_none());
+        assert!(TokenRing::new(1, &full, 0).is_some());
+    }
+}
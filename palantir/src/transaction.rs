@@ -0,0 +1,253 @@
+use crate::common::Address;
+use crate::error::Error;
+use crate::messages::{Frame, Message};
+use crate::time::{elapsed, Instant};
+use crate::{Bus, Palantir};
+
+/// Identifies one request and its response. Valid IDs are 1 to 127.
+pub type TransactionId = u8;
+
+/// Most requests a node can have waiting for a response at once.
+pub const MAX_PENDING: usize = 4;
+/// How long `Palantir::response` waits before giving up, unless changed with
+/// `Palantir::set_request_timeout`.
+pub const DEFAULT_REQUEST_TIMEOUT_US: u32 = 10_000;
+
+const RESPONSE_BIT: u8 = 0x80;
+
+/// The transaction byte in the frame header. Frames sent with plain
+/// `Palantir::send` carry none.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Transaction {
+    /// Answer with `Palantir::respond` and this ID.
+    Request(TransactionId),
+    Response(TransactionId),
+}
+
+impl Transaction {
+    pub(crate) fn from_byte(byte: u8) -> Option<Self> {
+        let id = byte & !RESPONSE_BIT;
+        match (id, byte & RESPONSE_BIT != 0) {
+            (0, _) => None,
+            (id, false) => Some(Transaction::Request(id)),
+            (id, true) => Some(Transaction::Response(id)),
+        }
+    }
+
+    pub(crate) fn to_byte(transaction: Option<Self>) -> u8 {
+        match transaction {
+            None => 0,
+            Some(Transaction::Request(id)) => id & !RESPONSE_BIT,
+            Some(Transaction::Response(id)) => id | RESPONSE_BIT,
+        }
+    }
+}
+
+/// Handle to a request sent with `Palantir::request`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct PendingResponse {
+    pub(crate) transaction: TransactionId,
+}
+
+impl PendingResponse {
+    pub fn transaction(&self) -> TransactionId {
+        self.transaction
+    }
+}
+
+#[derive(Default, Debug)]
+struct Slot {
+    /// 0 while the slot is free.
+    transaction: TransactionId,
+    sent_at: Instant,
+    response: Option<Message>,
+}
+
+/// Requests still waiting for their response.
+#[derive(Debug)]
+pub(crate) struct PendingTable {
+    slots: [Slot; MAX_PENDING],
+    last_transaction: TransactionId,
+}
+
+impl PendingTable {
+    pub fn new() -> Self {
+        PendingTable {
+            slots: Default::default(),
+            last_transaction: 0,
+        }
+    }
+
+    /// Reserves a slot and picks an ID no other pending request uses. When
+    /// all slots are taken, one whose request is older than `timeout_us` is
+    /// reused, so abandoned requests don't leak.
+    pub fn open(&mut self, now: Instant, timeout_us: u32) -> Option<PendingResponse> {
+        let free = self
+            .slots
+            .iter()
+            .position(|slot| slot.transaction == 0)
+            .or_else(|| {
+                self.slots
+                    .iter()
+                    .position(|slot| elapsed(slot.sent_at, now) >= timeout_us)
+            })?;
+        self.slots[free] = Slot::default();
+        let mut id = self.last_transaction;
+        loop {
+            id = if id >= 0x7F { 1 } else { id + 1 };
+            if !self.slots.iter().any(|slot| slot.transaction == id) {
+                break;
+            }
+        }
+        self.last_transaction = id;
+        self.slots[free] = Slot {
+            transaction: id,
+            sent_at: now,
+            response: None,
+        };
+        Some(PendingResponse { transaction: id })
+    }
+
+    fn find(&mut self, transaction: TransactionId) -> Option<&mut Slot> {
+        if transaction == 0 {
+            return None;
+        }
+        self.slots
+            .iter_mut()
+            .find(|slot| slot.transaction == transaction)
+    }
+
+    /// Stores `message` as the response to `transaction`. Returns `false` if
+    /// nothing is waiting for it, e.g. because it already timed out.
+    pub fn complete(&mut self, transaction: TransactionId, message: Message) -> bool {
+        match self.find(transaction) {
+            Some(slot) if slot.response.is_none() => {
+                slot.response = Some(message);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn close(&mut self, transaction: TransactionId) {
+        if let Some(slot) = self.find(transaction) {
+            *slot = Slot::default();
+        }
+    }
+
+    /// `None` if `transaction` isn't pending, otherwise the response if there
+    /// is one yet and whether `timeout_us` has passed since the request.
+    pub fn poll(
+        &mut self,
+        transaction: TransactionId,
+        now: Instant,
+        timeout_us: u32,
+    ) -> Option<(Option<Message>, bool)> {
+        let slot = self.find(transaction)?;
+        let response = slot.response.take();
+        let expired = elapsed(slot.sent_at, now) >= timeout_us;
+        Some((response, expired))
+    }
+}
+
+/// What `Sequencer::poll` did.
+pub(crate) enum Step {
+    /// Read a frame that isn't a response, the caller hands it on.
+    Frame(Frame),
+    /// Sent the request, or it is still waiting.
+    Waiting,
+    /// The request is done, with its response or `None` if it timed out.
+    Done(Option<Message>),
+}
+
+/// Sends requests one after the other, each once the last one is done. For
+/// the master-side jobs that go through a list of slaves, e.g.
+/// `device::Inventory`.
+#[derive(Debug, Default)]
+pub(crate) struct Sequencer {
+    pending: Option<PendingResponse>,
+}
+
+impl Sequencer {
+    /// Reads the bus, then sends `request` to its address or checks on the
+    /// one already sent. `request` should stay the same until it is `Done`,
+    /// and be `None` when there's nothing left to ask. Never blocks and
+    /// sends at most one frame.
+    pub fn poll<B: Bus, const Q: usize>(
+        &mut self,
+        palantir: &mut Palantir<B, Q>,
+        request: Option<(Address, Message)>,
+        now: Instant,
+    ) -> Result<Step, Error<B::Error>> {
+        match palantir.read() {
+            Ok(frame) => return Ok(Step::Frame(frame)),
+            Err(nb::Error::WouldBlock) => (),
+            Err(nb::Error::Other(e)) => return Err(e),
+        }
+
+        let (address, message) = match request {
+            Some(request) => request,
+            None => return Ok(Step::Waiting),
+        };
+        let pending = match self.pending {
+            Some(pending) => pending,
+            None => {
+                self.pending = Some(palantir.request(address, &message, now)?);
+                return Ok(Step::Waiting);
+            }
+        };
+        let response = match palantir.response(&pending, now) {
+            Ok(response) => Some(response),
+            Err(nb::Error::Other(Error::Timeout)) => None,
+            Err(nb::Error::WouldBlock) => return Ok(Step::Waiting),
+            Err(nb::Error::Other(e)) => return Err(e),
+        };
+        self.pending = None;
+        Ok(Step::Done(response))
+    }
+
+    /// Forgets the request being waited for.
+    pub fn reset(&mut self) {
+        self.pending = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transaction_byte_round_trip() {
+        for transaction in [
+            None,
+            Some(Transaction::Request(1)),
+            Some(Transaction::Request(0x7F)),
+            Some(Transaction::Response(42)),
+        ] {
+            assert_eq!(
+                Transaction::from_byte(Transaction::to_byte(transaction)),
+                transaction
+            );
+        }
+        assert_eq!(Transaction::from_byte(0x80), None);
+    }
+
+    #[test]
+    fn ids_skip_pending_and_wrap() {
+        let mut table = PendingTable::new();
+        table.last_transaction = 0x7E;
+        let a = table.open(0, 100).unwrap();
+        let b = table.open(0, 100).unwrap();
+        assert_eq!((a.transaction(), b.transaction()), (0x7F, 1));
+
+        table.last_transaction = 0x7E;
+        assert_eq!(table.open(0, 100).unwrap().transaction(), 2);
+        assert!(table.open(0, 100).is_some());
+        assert!(table.open(0, 100).is_none());
+
+        // Expired requests make room
+        assert_eq!(table.open(100, 100).unwrap().transaction(), 4);
+    }
+}
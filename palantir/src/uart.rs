@@ -0,0 +1,262 @@
+//! SAMD21 SERCOM USART line settings and baud register math.
+//!
+//! Kept apart from `feather_bus` so it builds and is tested on the host.
+
+/// How the SERCOM derives the baud rate from its clock.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum BaudMode {
+    /// `BAUD = 65536 * (1 - S * f_baud / f_ref)`. Fine-grained at low rates.
+    Arithmetic,
+    /// `f_baud = f_ref / (S * (BAUD + FP / 8))`. Better at high rates.
+    Fractional,
+    /// Whichever of the two gets closer to the requested rate.
+    Auto,
+}
+
+/// Samples taken per bit. Fewer allow higher rates for the same clock but
+/// tolerate less clock mismatch.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SampleRate {
+    X16,
+    X8,
+    /// Only available in arithmetic mode.
+    X3,
+}
+
+impl SampleRate {
+    pub fn samples(self) -> u32 {
+        match self {
+            SampleRate::X16 => 16,
+            SampleRate::X8 => 8,
+            SampleRate::X3 => 3,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StopBits {
+    One,
+    Two,
+}
+
+/// Largest baud rate error `UartConfig::reaches` accepts, in thousandths.
+pub const MAX_BAUD_ERROR_PERMILLE: u32 = 20;
+
+/// Line settings for `UartBus`. Build with `UartConfig::new(baud)` and the
+/// setters, everything else defaults to 16x sampling, automatic baud mode
+/// and one stop bit.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct UartConfig {
+    pub baud: u32,
+    pub baud_mode: BaudMode,
+    pub sample_rate: SampleRate,
+    pub stop_bits: StopBits,
+}
+
+impl UartConfig {
+    pub fn new(baud: u32) -> Self {
+        UartConfig {
+            baud,
+            baud_mode: BaudMode::Auto,
+            sample_rate: SampleRate::X16,
+            stop_bits: StopBits::One,
+        }
+    }
+
+    pub fn baud_mode(mut self, baud_mode: BaudMode) -> Self {
+        self.baud_mode = baud_mode;
+        self
+    }
+
+    pub fn sample_rate(mut self, sample_rate: SampleRate) -> Self {
+        self.sample_rate = sample_rate;
+        self
+    }
+
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.stop_bits = stop_bits;
+        self
+    }
+
+    /// Register values that get closest to the requested rate with a SERCOM
+    /// clocked at `clock_hz`. `None` if the rate is out of reach.
+    pub fn baud_setting(&self, clock_hz: u32) -> Option<BaudSetting> {
+        let arithmetic = || arithmetic_setting(self.baud, clock_hz, self.sample_rate);
+        let fractional = || fractional_setting(self.baud, clock_hz, self.sample_rate);
+        match self.baud_mode {
+            BaudMode::Arithmetic => arithmetic(),
+            BaudMode::Fractional => fractional(),
+            BaudMode::Auto => match (arithmetic(), fractional()) {
+                (Some(a), Some(f)) if f.error(self.baud) < a.error(self.baud) => Some(f),
+                (Some(a), _) => Some(a),
+                (None, f) => f,
+            },
+        }
+    }
+
+    /// Whether a SERCOM clocked at `clock_hz` gets close enough to the
+    /// requested rate to talk to other nodes, see `MAX_BAUD_ERROR_PERMILLE`.
+    pub fn reaches(&self, clock_hz: u32) -> bool {
+        let max_error = self.baud as u64 * MAX_BAUD_ERROR_PERMILLE as u64 / 1000;
+        self.baud_setting(clock_hz)
+            .is_some_and(|setting| setting.error(self.baud) as u64 <= max_error)
+    }
+}
+
+/// What goes in the BAUD register and CTRLA.SAMPR for a given rate.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct BaudSetting {
+    /// `Arithmetic` or `Fractional`, never `Auto`.
+    pub mode: BaudMode,
+    /// BAUD, or its integer part in fractional mode.
+    pub baud: u16,
+    /// Eighths added to `baud` in fractional mode, 0 otherwise.
+    pub fp: u8,
+    pub sampr: u8,
+    /// The rate these values actually produce.
+    pub actual_baud: u32,
+}
+
+impl BaudSetting {
+    /// How far off the requested `baud` this is, in Hz.
+    pub fn error(&self, baud: u32) -> u32 {
+        (self.actual_baud as i64 - baud as i64).unsigned_abs() as u32
+    }
+}
+
+const SHIFT: u64 = 32;
+
+/// Arithmetic mode BAUD value, computed the same way as Atmel's ASF.
+pub fn calculate_baud_value(baudrate: u32, clk_freq: u32, n_samples: u8) -> u16 {
+    let sample_rate = (n_samples as u64 * baudrate as u64) << 32;
+    let ratio = sample_rate / clk_freq as u64;
+    let scale = (1u64 << SHIFT) - ratio;
+    let baud_calculated = (65536u64 * scale) >> SHIFT;
+
+    baud_calculated as u16
+}
+
+/// Fractional mode BAUD and FP values, rounded to the nearest eighth.
+pub fn calculate_fractional_baud_value(baudrate: u32, clk_freq: u32, n_samples: u8) -> (u16, u8) {
+    let divisor = n_samples as u64 * baudrate as u64;
+    let eighths = (8 * clk_freq as u64 + divisor / 2) / divisor;
+    ((eighths / 8) as u16, (eighths % 8) as u8)
+}
+
+fn arithmetic_setting(baud: u32, clock_hz: u32, sample_rate: SampleRate) -> Option<BaudSetting> {
+    let samples = sample_rate.samples();
+    if baud == 0 || baud as u64 * samples as u64 >= clock_hz as u64 {
+        return None;
+    }
+    let value = calculate_baud_value(baud, clock_hz, samples as u8);
+    let actual = clock_hz as u64 * (65536 - value as u64) / (samples as u64 * 65536);
+    Some(BaudSetting {
+        mode: BaudMode::Arithmetic,
+        baud: value,
+        fp: 0,
+        sampr: match sample_rate {
+            SampleRate::X16 => 0,
+            SampleRate::X8 => 2,
+            SampleRate::X3 => 4,
+        },
+        actual_baud: actual as u32,
+    })
+}
+
+fn fractional_setting(baud: u32, clock_hz: u32, sample_rate: SampleRate) -> Option<BaudSetting> {
+    let sampr = match sample_rate {
+        SampleRate::X16 => 1,
+        SampleRate::X8 => 3,
+        SampleRate::X3 => return None,
+    };
+    let samples = sample_rate.samples();
+    if baud == 0 {
+        return None;
+    }
+    let (value, fp) = calculate_fractional_baud_value(baud, clock_hz, samples as u8);
+    // BAUD is 13 bits wide in fractional mode
+    if value == 0 || value > 0x1FFF {
+        return None;
+    }
+    let eighths = 8 * value as u64 + fp as u64;
+    let actual = 8 * clock_hz as u64 / (samples as u64 * eighths);
+    Some(BaudSetting {
+        mode: BaudMode::Fractional,
+        baud: value,
+        fp,
+        sampr,
+        actual_baud: actual as u32,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MHZ_48: u32 = 48_000_000;
+    const MHZ_8: u32 = 8_000_000;
+
+    #[test]
+    fn arithmetic_values() {
+        // 65536 * (1 - 16 * baud / f_ref), truncated like ASF does
+        assert_eq!(calculate_baud_value(9600, MHZ_48, 16), 65326);
+        assert_eq!(calculate_baud_value(115_200, MHZ_48, 16), 63019);
+        assert_eq!(calculate_baud_value(9600, MHZ_8, 16), 64277);
+        assert_eq!(calculate_baud_value(1_000_000, MHZ_48, 16), 43690);
+    }
+
+    #[test]
+    fn fractional_values() {
+        // f_ref / (16 * baud) = BAUD + FP / 8
+        assert_eq!(calculate_fractional_baud_value(9600, MHZ_48, 16), (312, 4));
+        assert_eq!(
+            calculate_fractional_baud_value(115_200, MHZ_48, 16),
+            (26, 0)
+        );
+        assert_eq!(
+            calculate_fractional_baud_value(1_000_000, MHZ_48, 16),
+            (3, 0)
+        );
+        assert_eq!(calculate_fractional_baud_value(250_000, MHZ_8, 16), (2, 0));
+    }
+
+    #[test]
+    fn auto_picks_smallest_error() {
+        // 48 MHz / 16 divides into 1 Mbaud exactly in fractional mode
+        let setting = UartConfig::new(1_000_000).baud_setting(MHZ_48).unwrap();
+        assert_eq!(setting.mode, BaudMode::Fractional);
+        assert_eq!(setting.actual_baud, 1_000_000);
+        assert_eq!(setting.sampr, 1);
+
+        // Fractional would give 115384
+        let setting = UartConfig::new(115_200).baud_setting(MHZ_48).unwrap();
+        assert_eq!(setting.mode, BaudMode::Arithmetic);
+        assert_eq!(setting.actual_baud, 115_219);
+        assert_eq!(setting.sampr, 0);
+        assert!(UartConfig::new(115_200).reaches(MHZ_48));
+    }
+
+    #[test]
+    fn beyond_one_megabaud() {
+        let setting = UartConfig::new(3_000_000).baud_setting(MHZ_48).unwrap();
+        assert_eq!(
+            (setting.mode, setting.baud, setting.fp),
+            (BaudMode::Fractional, 1, 0)
+        );
+        assert_eq!(setting.actual_baud, 3_000_000);
+
+        assert_eq!(UartConfig::new(4_000_000).baud_setting(MHZ_48), None);
+        assert!(!UartConfig::new(4_000_000).reaches(MHZ_48));
+        let config = UartConfig::new(4_000_000).sample_rate(SampleRate::X8);
+        assert_eq!(config.baud_setting(MHZ_48).unwrap().actual_baud, 4_000_000);
+        let forced = config
+            .baud_mode(BaudMode::Fractional)
+            .sample_rate(SampleRate::X3);
+        assert_eq!(forced.baud_setting(MHZ_48), None);
+    }
+}
@@ -15,7 +15,7 @@ use hal::{
 };
 use palantir::{self, feather_bus as bus, Palantir};
 
-use bus::UartBus;
+use bus::FeatherUartBus;
 
 const DEVICE_ADDRESS: u8 = 0x2;
 
@@ -26,7 +26,7 @@ type ErrorLEDPin = Pa16<Output<PushPull>>;
 #[rtfm::app(device = hal::pac)]
 const APP: () = {
     struct Resources {
-        palantir: Palantir<UartBus<ReceiveEnablePin>>,
+        palantir: Palantir<FeatherUartBus<ReceiveEnablePin>>,
         sercom0: hal::pac::SERCOM0,
         error_led: ErrorLEDPin,
         status_led: StatusLEDPin,
@@ -46,7 +46,7 @@ const APP: () = {
         let mut transmit_enable = pins.a4.into_push_pull_output(&mut pins.port);
         transmit_enable.set_low().unwrap();
 
-        let uart = UartBus::easy_new(
+        let uart = FeatherUartBus::easy_new(
             &mut clocks,
             peripherals.SERCOM0,
             &mut peripherals.PM,
@@ -69,11 +69,13 @@ const APP: () = {
         }
     }
 
-    #[idle(resources = [palantir, status_led])]
+    #[idle(resources = [palantir, status_led, error_led])]
     fn idle(cx: idle::Context) -> ! {
         let mut palantir = cx.resources.palantir;
-        let _msg = palantir.lock(|p| p.discovery_mode());
-        cx.resources.status_led.set_high().unwrap();
+        match palantir.lock(|p| p.discovery_mode()) {
+            Ok(_) => cx.resources.status_led.set_high().unwrap(),
+            _ => cx.resources.error_led.set_high().unwrap(),
+        };
         loop {}
     }
 